@@ -129,15 +129,6 @@ fn HomePage() -> impl IntoView {
     }
 }
 
-/// Check whether a name is acceptable to use
-fn validate_name(name: &str) -> bool {
-    let name = name.trim().to_lowercase();
-    !(name.ends_with("(bot)")
-        | name.contains("system")
-        | name.contains("admin")
-        | (name.is_empty()))
-}
-
 #[component]
 fn SelectName(set_name: WriteSignal<Option<String>>) -> impl IntoView {
     let input_node_ref: NodeRef<Input> = NodeRef::new();
@@ -146,7 +137,7 @@ fn SelectName(set_name: WriteSignal<Option<String>>) -> impl IntoView {
         let Some(name) = input_node_ref.get().map(|v| v.value()) else {
             return;
         };
-        if validate_name(&name) {
+        if crate::socket::validate_name(&name) {
             set_name.set(Some(name.trim().to_string()));
         }
     };
@@ -192,13 +183,29 @@ fn Feed(name: String) -> impl IntoView {
     ));
 
     let users = connection.users();
+    let own_status = {
+        let name = name.clone();
+        Memo::new(move |_| {
+            users
+                .get()
+                .into_iter()
+                .find(|i| i.name == name)
+                .and_then(|i| i.custom_status)
+        })
+    };
+    let set_custom_status = {
+        let connection = connection.clone();
+        move |status: Option<CustomStatus>| {
+            connection.set_custom_status(status)
+        }
+    };
     let users = {
         let name = name.clone();
         Memo::new(move |_| {
             users
                 .get()
                 .into_iter()
-                .filter(|i| i.0 != name)
+                .filter(|i| i.name != name)
                 .collect::<Vec<_>>()
         })
     };
@@ -212,50 +219,84 @@ fn Feed(name: String) -> impl IntoView {
         let vis = if web_sys::VisibilityState::Visible != visibility.get() {
         } else {
         };
+        let ready = connection.ready();
+        // Each new connection starts out hidden on the server, so visibility
+        // is reported again whenever the socket (re)opens
         Effect::new(move || {
-            conn.update_visiblity(visibility.get());
+            if ready.get() == leptos_use::core::ConnectionReadyState::Open {
+                conn.update_visiblity(visibility.get());
+            }
         });
     }
 
-    // Handle read receipts and notifications when receiving new messages
+    // Handle notifications when receiving new messages
     {
         use leptos::web_sys::VisibilityState;
         let conn = connection.clone();
         Effect::new(move || {
-            if let Some(ServerMessage::MessageSent { message }) =
-                conn.message.get()
-            {
-                match visibility.get_untracked() {
-                    VisibilityState::Hidden => {
-                        (use_web_notification().show)(
-                            leptos_use::ShowOptions::default()
-                                .title(format!(
-                                    "Message from {}",
-                                    message.sender
-                                ))
-                                .body(message.get_short()),
-                        );
-                    }
-                    VisibilityState::Visible => conn.read_messages(),
-                    _ => (),
+            // Streamed messages are announced before they have any text, so
+            // they notify once they're finished instead
+            let message = match conn.message.get() {
+                Some(ServerMessage::MessageSent { message })
+                    if message.message_html_safe.is_some() =>
+                {
+                    message
                 }
+                Some(ServerMessage::MessageFinalized { message }) => message,
+                _ => return,
+            };
+            if visibility.get_untracked() == VisibilityState::Hidden {
+                (use_web_notification().show)(
+                    leptos_use::ShowOptions::default()
+                        .title(format!("Message from {}", message.sender))
+                        .body(message.get_short()),
+                );
             }
         });
     }
-    // Read receipts when returning to the page
+    // Read receipts are sent for messages that actually scroll into view while
+    // the page is visible
+    let page_visible = Signal::derive(move || {
+        visibility.get() == leptos::web_sys::VisibilityState::Visible
+    });
     {
         let conn = connection.clone();
-        Effect::new(move |prev: Option<bool>| {
-            if visibility.get() == leptos::web_sys::VisibilityState::Visible {
-                if prev.is_some_and(|v| !v) {
-                    conn.read_messages();
-                }
-                true
-            } else {
-                false
+        Effect::new(move || conn.set_page_visible(page_visible.get()));
+    }
+    let on_seen = {
+        let conn = connection.clone();
+        Callback::new(move |id: u32| conn.mark_read(id))
+    };
+
+    let unread = connection.unread();
+    let unread_count = Memo::new(move |_| unread.get().len());
+    // The "new messages" divider is placed using the watermark from when the
+    // user last left the page, so it stays put while they catch up
+    let divider_watermark = RwSignal::new(None::<u32>);
+    {
+        let read_watermark = connection.read_watermark();
+        Effect::new(move || {
+            let watermark = read_watermark.get();
+            let left_page = !page_visible.get();
+            if left_page || divider_watermark.get_untracked().is_none() {
+                divider_watermark.set(watermark);
             }
         });
     }
+    let divider_before = {
+        let unread_since_left =
+            connection.unread_after(divider_watermark.into());
+        Signal::derive(move || unread_since_left.get().first().copied())
+    };
+    let jump_to_first_unread = move |_| {
+        let Some(id) = unread.get_untracked().first().copied() else {
+            return;
+        };
+        if let Some(el) = document().get_element_by_id(&format!("message-{id}"))
+        {
+            el.scroll_into_view();
+        }
+    };
 
     let (message_input, set_message_input) = signal(String::new());
     let message_node_ref: NodeRef<Textarea> = NodeRef::new();
@@ -279,7 +320,7 @@ fn Feed(name: String) -> impl IntoView {
         "absolute right-8 bottom-28 p-4 rounded shadow transition-all bg-white"
             .to_string()
             + if users_info_open.get() {
-                " w-60 h-60"
+                " w-60 h-60 overflow-y-auto"
             } else {
                 " hover:bg-gray-200 active:bg-gray-400 hover:cursor-pointer"
             }
@@ -299,15 +340,32 @@ fn Feed(name: String) -> impl IntoView {
     let reply_message = Signal::derive(reply_message);
 
     view! {
+        <Title text=move || match unread_count.get() {
+            0 => "RSS Chat".to_string(),
+            n => format!("({n}) RSS Chat"),
+        } />
+        <ReloadBanner reload_required=connection.reload_required() />
+        {move || (unread_count.get() > 0).then(|| view! {
+            <button
+                class="absolute left-8 bottom-28 p-2 rounded shadow bg-white hover:bg-gray-200 active:bg-gray-400 transition"
+                on:click=jump_to_first_unread
+            >
+                {format!("Jump to first unread ({})", unread_count.get())}
+            </button>
+        })}
+        <ErrorBanner error=connection.error() />
         {
+            // Bots type too, but they never show up as online users
+            let typing = connection.typing();
+            let name = name.clone();
             move || {
-                let typing_users: Vec<_> = users
+                let typing_users: Vec<_> = typing
                     .get()
                     .into_iter()
-                    .filter(|i| i.1)
+                    .filter(|i| *i != name)
                     .map(|i| view! {
                         <li>
-                            {i.0} " is typing..."
+                            {i} " is typing..."
                         </li>
                     })
                     .collect();
@@ -346,11 +404,19 @@ fn Feed(name: String) -> impl IntoView {
             {move || match users_info_open.get() {
                 true => view!{
                     <UsersList users=Signal::derive(users) close=move || set_users_info_open.set(false) />
+                    <StatusEditor current=own_status.into() set_status=set_custom_status.clone() />
                 }.into_any(),
                 false => view!{"Users"}.into_any(),
             }}
         </div>
-        <Messages messages=messages name=name.clone() set_reply=set_reply />
+        <Messages
+            messages=messages
+            name=name.clone()
+            set_reply=set_reply
+            page_visible=page_visible
+            on_seen=on_seen
+            divider_before=divider_before
+        />
         <div class="fixed bottom-0 left-0 flex w-screen flex-col items-center justify-center">
             <ReplyInfo message=reply_message />
             <form
@@ -396,6 +462,57 @@ fn Feed(name: String) -> impl IntoView {
     }
 }
 
+/// Asks the user to reload when the server speaks a newer protocol
+#[component]
+fn ReloadBanner(reload_required: ReadSignal<bool>) -> impl IntoView {
+    move || {
+        if reload_required.get() {
+            view! {
+                <div class="fixed top-0 left-0 z-10 flex w-screen flex-row items-center justify-center gap-4 bg-yellow-100 p-3 shadow">
+                    "RSS Chat has been updated. Please reload the page to keep chatting."
+                    <button
+                        class="p-2 rounded shadow bg-white hover:bg-gray-200 active:bg-gray-400 transition"
+                        on:click=|_| {
+                            let _ = window().location().reload();
+                        }
+                    >
+                        "Reload"
+                    </button>
+                </div>
+            }
+            .into_any()
+        } else {
+            ().into_any()
+        }
+    }
+}
+
+/// Shows why the server closed the connection
+#[component]
+fn ErrorBanner(
+    error: ReadSignal<Option<(crate::socket::ProtocolErrorCode, String)>>,
+) -> impl IntoView {
+    use crate::socket::ProtocolErrorCode;
+    move || {
+        if let Some((code, message)) = error.get() {
+            let hint = match code {
+                ProtocolErrorCode::ServerUnavailable => {
+                    "Please try again in a moment."
+                }
+                _ => "Reloading the page may help.",
+            };
+            view! {
+                <div class="fixed top-0 left-0 z-10 w-screen bg-red-100 p-3 text-center shadow">
+                    "Disconnected from the server: " {message} ". " {hint}
+                </div>
+            }
+            .into_any()
+        } else {
+            ().into_any()
+        }
+    }
+}
+
 #[component]
 fn ReplyInfo(message: Signal<Option<UserMessageClient>>) -> impl IntoView {
     move || {
@@ -430,6 +547,9 @@ fn Messages(
     messages: ReadSignal<Vec<ArcRwSignal<crate::socket::UserMessageClient>>>,
     name: String,
     set_reply: WriteSignal<Option<u32>>,
+    page_visible: Signal<bool>,
+    on_seen: Callback<u32>,
+    divider_before: Signal<Option<u32>>,
 ) -> impl IntoView {
     view! {
         <div>
@@ -441,7 +561,10 @@ fn Messages(
                     name=name.clone()
                     message=message.clone()
                     set_reply=set_reply
-                    messages=messages/>
+                    messages=messages
+                    page_visible=page_visible
+                    on_seen=on_seen
+                    divider_before=divider_before/>
             </For>
         </div>
     }
@@ -453,7 +576,21 @@ fn MessageDisplay(
     message: ArcRwSignal<UserMessageClient>,
     set_reply: WriteSignal<Option<u32>>,
     messages: ReadSignal<Vec<ArcRwSignal<UserMessageClient>>>,
+    page_visible: Signal<bool>,
+    on_seen: Callback<u32>,
+    divider_before: Signal<Option<u32>>,
 ) -> impl IntoView {
+    let node_ref: NodeRef<leptos::html::Div> = NodeRef::new();
+    let on_screen = leptos_use::use_element_visibility(node_ref);
+    {
+        let message = message.clone();
+        Effect::new(move || {
+            if on_screen.get() && page_visible.get() {
+                on_seen.run(message.get_untracked().message.id);
+            }
+        });
+    }
+
     let message_2 = message.clone();
     let reply_message = move || {
         message_2.get().message.reply_to.and_then(|r| {
@@ -465,8 +602,9 @@ fn MessageDisplay(
         })
     };
     let reply_message = Signal::derive(reply_message);
+    let id = message.get_untracked().message.id;
     view! {
-        <div class="hover:bg-gray-200 transition w-screen px-2 py-4">
+        <div class="hover:bg-gray-200 transition w-screen px-2 py-4" id=format!("message-{id}") node_ref=node_ref>
             {let message = message.clone(); let name = name.clone(); move || {
                 let read_by = message.get().read_by.into_iter().filter(|i| *i != name && *i != message.get().message.sender).collect::<Vec<_>>();
                 if read_by.is_empty() {
@@ -481,7 +619,14 @@ fn MessageDisplay(
                 <div class="grow pr-8">
                     <div class="font-bold text-gray-700">{ let message = message.clone(); move || message.get().message.sender }</div>
                     <ReplyInfo message=reply_message />
-                    <div inner_html={ let message = message.clone(); move || message.get().message.message_html_safe }></div>
+                    // Messages that are still being streamed only have their
+                    // raw text so far
+                    {let message = message.clone(); move || match message.get().message.message_html_safe {
+                        Some(html) => view! { <div inner_html=html></div> }.into_any(),
+                        None => view! {
+                            <div class="whitespace-pre-wrap">{message.get().message.message_md}</div>
+                        }.into_any(),
+                    }}
                 </div>
                 <div class="text-right text-gray-700 flex flex-row items-center shrink-0">
                     <div class="text-right w-full">
@@ -505,14 +650,38 @@ fn MessageDisplay(
                 </div>
             </div>
         </div>
+        // Messages are listed newest first, so the divider goes below the
+        // oldest unread message
+        {move || (divider_before.get() == Some(id)).then(|| view! {
+            <div class="flex flex-row items-center gap-2 px-2 text-sm text-red-600">
+                <hr class="grow border-red-600" />
+                "New messages"
+                <hr class="grow border-red-600" />
+            </div>
+        })}
     }
 }
 
 #[component]
 fn UsersList(
-    users: Signal<Vec<(String, bool, bool)>>,
+    users: Signal<Vec<crate::socket::UserInfo>>,
     close: impl Fn() + 'static,
 ) -> impl IntoView {
+    use crate::socket::UserStatus;
+    let online = move || {
+        users
+            .get()
+            .into_iter()
+            .filter(|i| i.status != UserStatus::Offline)
+            .collect::<Vec<_>>()
+    };
+    let offline = move || {
+        users
+            .get()
+            .into_iter()
+            .filter(|i| i.status == UserStatus::Offline)
+            .collect::<Vec<_>>()
+    };
     view! {
         <button
             class="p-1 rounded shadow absolute top-2 right-2"
@@ -523,28 +692,125 @@ fn UsersList(
         >
             "❌"
         </button>
-        {move || if users.get().is_empty() {
+        {move || if online().is_empty() {
             view! {
                 <p>"No other users online"</p>
             }.into_any()
         } else {
             view! {
                 <ol class="list-decimal">
-                    {users.get().into_iter().map(|i| {
+                    {online().into_iter().map(|i| {
                         let mut status = String::with_capacity(4);
-                        if i.1 {
+                        if i.typing {
                             status.push_str(" ⌨️");
                         }
-                        if i.2 {
+                        if i.observing {
                             status.push_str(" 👀");
                         }
+                        match i.status {
+                            UserStatus::Idle => status.push_str(" (idle)"),
+                            UserStatus::Away => status.push_str(" (away)"),
+                            _ => (),
+                        }
+                        let custom_status = i.custom_status.map(|v| view! {
+                            <div class="text-sm text-gray-600">{v.to_string()}</div>
+                        });
                         view!{
-                            <li class="ml-6">{i.0}{status}</li>
+                            <li class="ml-6">{i.name}{status}{custom_status}</li>
                         }
                     }).collect::<Vec<_>>()}
                 </ol>
             }.into_any()
         }}
+        {move || {
+            let offline = offline();
+            if offline.is_empty() {
+                ().into_any()
+            } else {
+                view! {
+                    <p class="mt-2 text-gray-700">"Offline"</p>
+                    <ul class="list-none">
+                        {offline.into_iter().map(|i| {
+                            let last_seen = i.last_seen.map(|v| format!(" - last seen {}", format_last_seen(v)));
+                            let custom_status = i.custom_status.map(|v| view! {
+                                <div class="text-sm">{v.to_string()}</div>
+                            });
+                            view!{
+                                <li class="ml-2 text-gray-500">{i.name}{last_seen}{custom_status}</li>
+                            }
+                        }).collect::<Vec<_>>()}
+                    </ul>
+                }.into_any()
+            }
+        }}
+    }
+}
+
+/// Lets the user set or clear their own custom status
+#[component]
+fn StatusEditor(
+    current: Signal<Option<crate::socket::CustomStatus>>,
+    set_status: impl Fn(Option<crate::socket::CustomStatus>) + Clone + 'static,
+) -> impl IntoView {
+    use crate::socket::CustomStatus;
+    let emoji_ref: NodeRef<Input> = NodeRef::new();
+    let text_ref: NodeRef<Input> = NodeRef::new();
+    let expiry_ref: NodeRef<leptos::html::Select> = NodeRef::new();
+
+    let on_submit = {
+        let set_status = set_status.clone();
+        move |ev: SubmitEvent| {
+            ev.prevent_default();
+            let (Some(emoji), Some(text), Some(expiry)) =
+                (emoji_ref.get(), text_ref.get(), expiry_ref.get())
+            else {
+                return;
+            };
+            let text = text.value().trim().to_string();
+            if text.is_empty() {
+                return;
+            }
+            let emoji = Some(emoji.value().trim().to_string())
+                .filter(|v| !v.is_empty());
+            let expires = expiry.value().parse::<i64>().ok().map(|minutes| {
+                chrono::Utc::now() + chrono::TimeDelta::minutes(minutes)
+            });
+            set_status(Some(CustomStatus {
+                emoji,
+                text,
+                expires,
+            }));
+        }
+    };
+
+    view! {
+        <form class="mt-4 flex flex-col gap-1" on:submit=on_submit>
+            <div class="text-gray-700">
+                "Your status: "
+                {move || current.get().map(|v| v.to_string()).unwrap_or_else(|| "none".to_string())}
+            </div>
+            <div class="flex flex-row gap-1">
+                <input node_ref=emoji_ref placeholder="🙂" class="w-10 p-1 bg-gray-50" />
+                <input node_ref=text_ref placeholder="What's up?" maxlength="100" class="w-full p-1 bg-gray-50" />
+            </div>
+            <select node_ref=expiry_ref class="p-1 bg-gray-50">
+                <option value="">"Don't clear"</option>
+                <option value="30">"Clear after 30 minutes"</option>
+                <option value="60">"Clear after 1 hour"</option>
+                <option value="240">"Clear after 4 hours"</option>
+                <option value="1440">"Clear after 1 day"</option>
+            </select>
+            <div class="flex flex-row gap-1">
+                <button type="submit" class="p-1 rounded shadow bg-white hover:bg-gray-200 active:bg-gray-400 transition">"Set"</button>
+                <button
+                    type="button"
+                    class="p-1 rounded shadow bg-white hover:bg-gray-200 active:bg-gray-400 transition"
+                    on:click=move |_| set_status(None)
+                >
+                    "Clear"
+                </button>
+            </div>
+        </form>
     }
 }
 
@@ -602,6 +868,17 @@ fn EmojiPicker(
     }
 }
 
+/// Formats how long ago a user was last seen, e.g. "5m ago"
+fn format_last_seen(datetime: chrono::DateTime<chrono::Utc>) -> String {
+    let secs = (chrono::Utc::now() - datetime).num_seconds().max(0);
+    match secs {
+        0..60 => "just now".to_string(),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
 fn format_datetime(datetime: chrono::DateTime<chrono::Utc>) -> String {
     let now = chrono::Utc::now();
     let is_today = now.date_naive() == datetime.date_naive();
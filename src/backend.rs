@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use async_openai::{
+    config::OpenAIConfig,
+    error::OpenAIError,
+    types::{
+        ChatCompletionResponseStream, CreateChatCompletionRequest,
+        CreateChatCompletionResponse,
+    },
+    Client,
+};
+use futures::future::BoxFuture;
+
+/// Something that answers chat completion requests. Normally that's an
+/// OpenAI-compatible API, but bots can also be run against a mock.
+pub trait ChatBackend: Send + Sync {
+    /// Requests a completion, returning it piece by piece as it's generated
+    fn create_stream(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> BoxFuture<'_, Result<ChatCompletionResponseStream, OpenAIError>>;
+    /// Requests a completion, returning all of it at once
+    fn create(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> BoxFuture<'_, Result<CreateChatCompletionResponse, OpenAIError>>;
+}
+
+/// Sends requests to an OpenAI-compatible API over HTTP
+pub struct OpenAiBackend {
+    client: Client<OpenAIConfig>,
+}
+
+impl OpenAiBackend {
+    pub fn new(api_base: String, api_key: Option<String>) -> OpenAiBackend {
+        OpenAiBackend {
+            client: Client::with_config(
+                OpenAIConfig::new()
+                    .with_api_key(api_key.unwrap_or_default())
+                    .with_api_base(api_base),
+            ),
+        }
+    }
+}
+
+impl ChatBackend for OpenAiBackend {
+    fn create_stream(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> BoxFuture<'_, Result<ChatCompletionResponseStream, OpenAIError>> {
+        Box::pin(async move { self.client.chat().create_stream(request).await })
+    }
+    fn create(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> BoxFuture<'_, Result<CreateChatCompletionResponse, OpenAIError>> {
+        Box::pin(async move { self.client.chat().create(request).await })
+    }
+}
+
+/// Shares a backend, so that whoever handed it over can still look at it
+impl<T: ChatBackend + ?Sized> ChatBackend for Arc<T> {
+    fn create_stream(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> BoxFuture<'_, Result<ChatCompletionResponseStream, OpenAIError>> {
+        (**self).create_stream(request)
+    }
+    fn create(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> BoxFuture<'_, Result<CreateChatCompletionResponse, OpenAIError>> {
+        (**self).create(request)
+    }
+}
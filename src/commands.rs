@@ -1,46 +1,74 @@
+use std::{future::Future, time::Duration};
+
 use crate::{ai, AppStateExt as AppState, ServerStateMessage};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Utc};
 use rss_chat::socket::UserMessage;
 use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+
+/// How often streamed bot replies are forwarded to clients. Sending tokens in
+/// batches keeps the number of websocket messages down.
+const STREAM_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Most bots a single message can summon by mentioning them
+const MAX_MENTIONED_BOTS: usize = 3;
+
+/// Messages summarized by `%summarize` when no range is given
+const DEFAULT_SUMMARY_MESSAGES: usize = 50;
+/// Most messages that go into one summary
+const MAX_SUMMARY_MESSAGES: usize = 200;
 
 pub async fn react_to_message(message: UserMessage, state: AppState) {
+    // The server's own messages can quote anything, like exported bots, and
+    // shouldn't set bots off
+    if message.sender == "System" {
+        return;
+    }
     let user = message.sender.clone();
-    let send_msg = move |msg: String, sender: String| async move {
-        let _ = state
-            .state_tx
-            .send(ServerStateMessage::NewMessage {
-                message: UserMessage {
-                    send_time: chrono::Utc::now(),
-                    sender,
-                    message_md: msg,
-                    message_short: None,
-                    message_html_safe: None,
-                    reply_to: message.reply_to,
-                    id: 0,
-                },
-            })
-            .await;
+    // Replies are threaded under the message that asked for them
+    let reply_to = Some(message.id);
+    let send_sysmsg = {
+        let state_tx = state.state_tx.clone();
+        move |msg: String| async move {
+            let _ = state_tx
+                .send(ServerStateMessage::NewMessage {
+                    message: new_message(msg, "System".to_string(), reply_to),
+                })
+                .await;
+        }
     };
-    let send_msgc = send_msg.clone();
-    let send_sysmsg = move |msg: String| send_msgc(msg, "System".to_string());
     match parse_commands(&message.message_md) {
         Some(Ok(command)) => match command {
             MessageCommand::AIQuery { bot, query } => {
-                let response = state
-                    .ai_context
-                    .get_response(&query, &user, bot.as_deref())
-                    .await;
-                match response {
-                    Ok(response) => {
-                        send_msg(
-                            response.response,
-                            format!("{} (Bot)", response.bot_name),
-                        )
-                        .await;
+                if let Err(e) = stream_bot_reply(
+                    &state,
+                    &query,
+                    &user,
+                    bot.as_deref(),
+                    reply_to,
+                )
+                .await
+                {
+                    send_sysmsg(format!("Bot could not respond:\n{e}")).await;
+                }
+            }
+            MessageCommand::Summarize(range) => {
+                let messages = match range {
+                    SummaryRange::Last(count) => {
+                        chat_messages(&state, count, None).await
                     }
-                    Err(e) => {
-                        send_sysmsg(format!("Bot could not respond:\n{e}"))
-                            .await;
+                    SummaryRange::Since(since) => {
+                        chat_messages(&state, MAX_SUMMARY_MESSAGES, Some(since))
+                            .await
                     }
+                };
+                if messages.is_empty() {
+                    let text = "There are no messages to summarize";
+                    send_sysmsg(text.to_string()).await;
+                } else if let Err(e) =
+                    stream_summary(&state, &messages, &user, reply_to).await
+                {
+                    send_sysmsg(format!("Could not summarize:\n{e}")).await;
                 }
             }
             MessageCommand::Help => {
@@ -50,32 +78,439 @@ pub async fn react_to_message(message: UserMessage, state: AppState) {
                 let bots_list = state
                     .ai_context
                     .bots()
-                    .await
                     .into_iter()
-                    .map(|i| format!("- {}", i.name()))
+                    .map(|i| {
+                        let mut line = format!("- {}", i.name());
+                        if let Some(model) = i.model() {
+                            line.push_str(&format!(" ({model})"));
+                        }
+                        if i.context_messages() > 0 {
+                            line.push_str(&format!(
+                                ", sees the last {} messages",
+                                i.context_messages()
+                            ));
+                        }
+                        for param in describe_generation(i.generation()) {
+                            line.push_str(&format!(", {param}"));
+                        }
+                        line
+                    })
                     .collect::<Vec<_>>()
                     .join("\n");
                 send_sysmsg(format!("Bots online:\n{bots_list}")).await;
             }
-            MessageCommand::AICreate { name, lang, config } => {
-                let new_bot =
-                    ai::Bot::new(name.clone(), user, Some(config), lang);
-                state.ai_context.add_bot(new_bot).await;
-                send_sysmsg(format!("Bot {name} created")).await;
+            MessageCommand::AICreate { name, settings } => {
+                let new_bot = ai::Bot::new(name.clone(), user, settings);
+                match state.ai_context.add_bot(new_bot).await {
+                    Ok(()) => {
+                        send_sysmsg(format!("Bot {name} created")).await;
+                    }
+                    Err(e) => {
+                        send_sysmsg(format!("Could not create bot:\n{e}"))
+                            .await;
+                    }
+                }
+            }
+            MessageCommand::AIEdit { bot, settings } => {
+                match state.ai_context.edit_bot(&bot, &user, settings).await {
+                    Ok(()) => {
+                        send_sysmsg(format!("Bot {bot} updated")).await;
+                    }
+                    Err(e) => {
+                        send_sysmsg(format!("Could not edit bot:\n{e}"))
+                            .await;
+                    }
+                }
+            }
+            MessageCommand::AIUsage => {
+                let report = state.ai_context.usage(&user);
+                send_sysmsg(format_usage(&user, &report)).await;
+            }
+            MessageCommand::AIExport { bot } => {
+                match state.ai_context.export_bot(&bot) {
+                    Ok(persona) => {
+                        let json = serde_json::to_string_pretty(&persona)
+                            .unwrap_or_default();
+                        send_sysmsg(format!(
+                            "Bot {}, ready to be shared with `%importbot`:\n\
+```json\n{json}\n```",
+                            persona.name
+                        ))
+                        .await;
+                    }
+                    Err(e) => {
+                        send_sysmsg(format!("Could not export bot:\n{e}"))
+                            .await;
+                    }
+                }
+            }
+            MessageCommand::AIImport { persona } => {
+                let name = persona.name.clone();
+                let new_bot = ai::Bot::from_persona(persona, user);
+                match state.ai_context.add_bot(new_bot).await {
+                    Ok(()) => {
+                        send_sysmsg(format!("Bot {name} imported")).await;
+                    }
+                    Err(e) => {
+                        send_sysmsg(format!("Could not import bot:\n{e}"))
+                            .await;
+                    }
+                }
+            }
+            MessageCommand::AIReset { bot, everyone } => {
+                match state.ai_context.reset_bot(&bot, &user, everyone).await {
+                    Ok(name) => {
+                        let whose = if everyone {
+                            "its conversations with everyone"
+                        } else {
+                            "its conversation with you"
+                        };
+                        send_sysmsg(format!("Bot {name} forgot {whose}"))
+                            .await;
+                    }
+                    Err(e) => {
+                        send_sysmsg(format!("Could not reset bot:\n{e}"))
+                            .await;
+                    }
+                }
             }
             MessageCommand::AIRemove { bot } => {
-                if state.ai_context.remove_bot_by_name(bot).await.is_some() {
-                    send_sysmsg("Bot removed".to_string()).await;
-                } else {
-                    send_sysmsg("No such bot exists".to_string()).await;
+                match state.ai_context.remove_bot(&bot, &user).await {
+                    Ok(removed) => {
+                        send_sysmsg(format!("Bot {} removed", removed.name()))
+                            .await;
+                    }
+                    Err(e) => {
+                        send_sysmsg(format!("Could not remove bot:\n{e}"))
+                            .await;
+                    }
                 }
             }
         },
         Some(Err(e)) => {
             send_sysmsg(format!("Invalid command:\n{e}")).await;
         }
-        None => (),
+        None => {
+            let mut mentioned: Vec<String> = vec![];
+            for name in mentions(&message.message_md) {
+                let name = name.to_lowercase();
+                if !mentioned.contains(&name) {
+                    mentioned.push(name);
+                }
+            }
+            for name in mentioned.iter().take(MAX_MENTIONED_BOTS) {
+                // Most mentions are of people rather than bots, so failing
+                // to find a bot isn't worth telling anyone about
+                if let Err(e) = stream_bot_reply(
+                    &state,
+                    &message.message_md,
+                    &user,
+                    Some(name),
+                    reply_to,
+                )
+                .await
+                {
+                    match e {
+                        ai::AiResponseError::UsageLimit(e) => {
+                            send_sysmsg(format!("Bot could not respond:\n{e}"))
+                                .await;
+                            break;
+                        }
+                        e => log::debug!("Mention of {name} not answered: {e}"),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Names mentioned with `@name` in a message, in order of appearance. An `@`
+/// inside a word, like in an email address, isn't a mention.
+fn mentions(text: &str) -> Vec<&str> {
+    let mut mentions = vec![];
+    let mut prev = None;
+    for (i, c) in text.char_indices() {
+        if c == '@' && !prev.is_some_and(ai::is_name_char) {
+            let rest = &text[i + 1..];
+            let end = rest.find(|c| !ai::is_name_char(c)).unwrap_or(rest.len());
+            if end > 0 {
+                mentions.push(&rest[..end]);
+            }
+        }
+        prev = Some(c);
+    }
+    mentions
+}
+
+/// Lists the generation parameters a bot has set, for `%listbots`
+fn describe_generation(params: &ai::GenerationParams) -> Vec<String> {
+    let mut described = vec![];
+    if let Some(length) = params.length {
+        described.push(format!("{} replies", length.name()));
+    }
+    if let Some(temperature) = params.temperature {
+        described.push(format!("temperature {temperature}"));
+    }
+    if let Some(top_p) = params.top_p {
+        described.push(format!("top_p {top_p}"));
+    }
+    if let Some(max_tokens) = params.max_tokens {
+        described.push(format!("at most {max_tokens} tokens"));
+    }
+    if let Some(ref stop) = params.stop {
+        described.push(format!("stops at {stop:?}"));
+    }
+    described
+}
+
+fn format_usage(user: &str, report: &ai::UsageReport) -> String {
+    let daily_limit = report
+        .limits
+        .daily_tokens
+        .map_or(String::new(), |limit| format!(" of {limit}"));
+    let rate_limit = report
+        .limits
+        .requests_per_minute
+        .map_or(String::new(), |limit| format!(" of {limit}"));
+    let mut text = format!(
+        "Bot usage of {user}:
+- {} tokens today{}
+- {} tokens since the server started
+- {} requests in the last minute{}",
+        report.user.today.total(),
+        daily_limit,
+        report.user.total.total(),
+        report.user.requests_last_minute,
+        rate_limit,
+    );
+    if !report.bots.is_empty() {
+        text.push_str("\n\nTokens used by bots since the server started:");
+        for (bot, usage) in &report.bots {
+            text.push_str(&format!(
+                "\n- {bot}: {} ({} prompt, {} reply)",
+                usage.total(),
+                usage.prompt_tokens,
+                usage.completion_tokens
+            ));
+        }
     }
+    text
+}
+
+fn new_message(
+    message_md: String,
+    sender: String,
+    reply_to: Option<u32>,
+) -> UserMessage {
+    UserMessage {
+        send_time: chrono::Utc::now(),
+        sender,
+        message_md,
+        message_short: None,
+        message_html_safe: None,
+        reply_to,
+        id: 0,
+    }
+}
+
+/// Has a bot reply to a query, streaming the reply into the chat. Errors are
+/// only returned if the bot couldn't start replying; later ones end up in the
+/// reply itself.
+async fn stream_bot_reply(
+    state: &AppState,
+    query: &str,
+    user: &str,
+    bot: Option<&str>,
+    reply_to: Option<u32>,
+) -> Result<(), ai::AiResponseError> {
+    let bot = state.ai_context.resolve_bot(bot)?;
+    // Checked up front so that going over the limit doesn't leave an empty
+    // reply behind
+    state.ai_context.check_usage(user)?;
+    let context = if bot.context_messages > 0 {
+        recent_messages(state, bot.context_messages, None).await
+    } else {
+        vec![]
+    };
+    let bot_name = bot.name;
+    let sender = format!("{bot_name} (Bot)");
+    stream_reply(state, sender, reply_to, |deltas| async move {
+        let query = ai::Query {
+            text: query,
+            user,
+            context,
+        };
+        state
+            .ai_context
+            .get_response(query, Some(&bot_name), deltas)
+            .await
+    })
+    .await;
+    Ok(())
+}
+
+/// Has the default bot summarize messages for `user`, streaming the summary
+/// into the chat
+async fn stream_summary(
+    state: &AppState,
+    messages: &[UserMessage],
+    user: &str,
+    reply_to: Option<u32>,
+) -> Result<(), ai::AiResponseError> {
+    let bot = state.ai_context.resolve_bot(None)?;
+    state.ai_context.check_usage(user)?;
+    stream_reply(state, format!("{} (Bot)", bot.name), reply_to, |deltas| {
+        state.ai_context.summarize_chat(messages, Some(user), deltas)
+    })
+    .await;
+    Ok(())
+}
+
+/// Posts a summary of the last day of chat from the System user every day at
+/// `at` UTC, unless nothing was said
+pub async fn post_daily_digests(state: AppState, at: NaiveTime) {
+    loop {
+        let now = Utc::now();
+        let mut next = now.date_naive().and_time(at).and_utc();
+        if next <= now {
+            next += TimeDelta::days(1);
+        }
+        tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+
+        let since = next - TimeDelta::days(1);
+        let messages =
+            chat_messages(&state, MAX_SUMMARY_MESSAGES, Some(since)).await;
+        if messages.is_empty() {
+            continue;
+        }
+        if let Err(e) = state.ai_context.resolve_bot(None) {
+            log::error!("Could not post the daily digest:\n{e}");
+            continue;
+        }
+        let ai_context = &state.ai_context;
+        stream_reply(&state, "System".to_string(), None, |deltas| async move {
+            let heading = "**Daily digest**\n\n".to_string();
+            let _ = deltas.send(heading.clone());
+            let summary =
+                ai_context.summarize_chat(&messages, None, deltas).await?;
+            Ok(heading + &summary)
+        })
+        .await;
+    }
+}
+
+/// Posts an empty message from `sender` and fills it in with the text passed
+/// to the sender given to `generate`. If generating fails, the error ends up
+/// in the message.
+async fn stream_reply<F>(
+    state: &AppState,
+    sender: String,
+    reply_to: Option<u32>,
+    generate: impl FnOnce(mpsc::UnboundedSender<String>) -> F,
+) where
+    F: Future<Output = Result<String, ai::AiResponseError>>,
+{
+    let (id_tx, id_rx) = oneshot::channel();
+    let _ = state
+        .state_tx
+        .send(ServerStateMessage::StreamStarted {
+            message: new_message(String::new(), sender, reply_to),
+            id_tx,
+        })
+        .await;
+    // The server is shutting down if the message wasn't accepted
+    let Ok(id) = id_rx.await else {
+        return;
+    };
+
+    let (deltas_tx, deltas_rx) = mpsc::unbounded_channel();
+    let forwarder =
+        tokio::spawn(forward_deltas(id, deltas_rx, state.state_tx.clone()));
+    let response = generate(deltas_tx).await;
+    // Let the last deltas through before the message is finalized so they
+    // can't arrive after it
+    let _ = forwarder.await;
+
+    let message_md = match response {
+        Ok(response) => response,
+        Err(e) => format!("*Could not respond: {e}*"),
+    };
+    let _ = state
+        .state_tx
+        .send(ServerStateMessage::StreamFinished { id, message_md })
+        .await;
+}
+
+/// Fetches up to `count` of the latest finished chat messages, leaving out
+/// any sent before `since`
+async fn recent_messages(
+    state: &AppState,
+    count: usize,
+    since: Option<DateTime<Utc>>,
+) -> Vec<UserMessage> {
+    fetch_messages(state, count, since, false).await
+}
+
+/// Like [`recent_messages`], but only what people said to each other,
+/// without commands or System messages
+async fn chat_messages(
+    state: &AppState,
+    count: usize,
+    since: Option<DateTime<Utc>>,
+) -> Vec<UserMessage> {
+    fetch_messages(state, count, since, true).await
+}
+
+async fn fetch_messages(
+    state: &AppState,
+    count: usize,
+    since: Option<DateTime<Utc>>,
+    chat_only: bool,
+) -> Vec<UserMessage> {
+    let (messages_tx, messages_rx) = oneshot::channel();
+    let _ = state
+        .state_tx
+        .send(ServerStateMessage::RecentMessages {
+            count,
+            since,
+            chat_only,
+            messages_tx,
+        })
+        .await;
+    messages_rx.await.unwrap_or_default()
+}
+
+/// Forwards generated text to the message with the given id, batching it up
+/// until the sender is dropped
+async fn forward_deltas(
+    id: u32,
+    mut deltas: mpsc::UnboundedReceiver<String>,
+    state_tx: mpsc::Sender<ServerStateMessage>,
+) {
+    let mut interval = tokio::time::interval(STREAM_FLUSH_INTERVAL);
+    let mut pending = String::new();
+    loop {
+        tokio::select! {
+            delta = deltas.recv() => match delta {
+                Some(delta) => pending.push_str(&delta),
+                None => break,
+            },
+            _ = interval.tick() => {
+                if pending.is_empty() {
+                    continue;
+                }
+                let delta = std::mem::take(&mut pending);
+                if state_tx
+                    .send(ServerStateMessage::StreamDelta { id, delta })
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        }
+    }
+    // Whatever is still pending is part of the finished message anyway
 }
 
 enum MessageCommand {
@@ -85,20 +520,54 @@ enum MessageCommand {
     },
     AICreate {
         name: String,
-        lang: Option<String>,
-        config: String,
+        settings: ai::BotSettings,
+    },
+    AIEdit {
+        bot: String,
+        settings: ai::BotSettings,
     },
     AIRemove {
         bot: String,
     },
+    AIReset {
+        bot: String,
+        /// Whether to forget everyone's conversations rather than just the
+        /// sender's
+        everyone: bool,
+    },
+    AIExport {
+        bot: String,
+    },
+    AIImport {
+        persona: ai::Persona,
+    },
     AIList,
+    AIUsage,
+    Summarize(SummaryRange),
     Help,
 }
 
+/// Which messages `%summarize` covers
+enum SummaryRange {
+    /// The latest few messages
+    Last(usize),
+    /// Messages sent since a time
+    Since(DateTime<Utc>),
+}
+
 #[derive(Debug, Error)]
 enum MessageParseError {
     #[error("Invalid command or command syntax entered")]
     InvalidCommand,
+    #[error("Invalid value \"{value}\" for option {key}")]
+    InvalidOption { key: String, value: String },
+    #[error("Invalid bot persona: {0}")]
+    MalformedPersona(serde_json::Error),
+    #[error(
+        "Invalid time \"{0}\", expected HH:MM, YYYY-MM-DD or \
+YYYY-MM-DD HH:MM in UTC"
+    )]
+    InvalidTime(String),
 }
 
 fn parse_commands(
@@ -134,44 +603,813 @@ fn parse_commands(
     } else if command == "newbot"
         && command_input.split_whitespace().count() > 2
     {
-        let name = command_input.split_whitespace().nth(1).unwrap();
-        let lang_word = command_input.split_whitespace().nth(2).unwrap();
-        let lang = lang_word.strip_prefix("lang=").map(|v| v.to_string());
-        let customizations = command_input
-            .chars()
-            .skip(6)
-            .skip_while(|c| c.is_whitespace())
-            .skip(name.len())
-            .skip_while(|c| c.is_whitespace());
-        let customizations_final: String = if let Some(ref lang) = lang {
-            customizations
-                .skip(lang.len() + 5)
-                .skip_while(|c| c.is_whitespace())
-                .collect()
-        } else {
-            customizations.collect()
+        let (name, options, config) = bot_args(command_input);
+        let mut settings = match bot_settings(&options) {
+            Ok(settings) => settings,
+            Err(e) => return Some(Err(e)),
         };
+        settings.custom_config = Some(config.to_string());
         Some(Ok(MessageCommand::AICreate {
             name: name.to_string(),
-            lang,
-            config: customizations_final,
+            settings,
+        }))
+    } else if command == "editbot"
+        && command_input.split_whitespace().count() > 2
+    {
+        let (name, options, config) = bot_args(command_input);
+        let mut settings = match bot_settings(&options) {
+            Ok(settings) => settings,
+            Err(e) => return Some(Err(e)),
+        };
+        settings.custom_config =
+            (!config.is_empty()).then(|| config.to_string());
+        Some(Ok(MessageCommand::AIEdit {
+            bot: name.to_string(),
+            settings,
         }))
     } else if command == "listbots" {
         Some(Ok(MessageCommand::AIList))
-    } else if command == "removebot" {
+    } else if command == "resetbot"
+        && let Some(bot) = command_input.split_whitespace().nth(1)
+    {
+        Some(Ok(MessageCommand::AIReset {
+            bot: bot.to_string(),
+            everyone: command_input.split_whitespace().nth(2) == Some("all"),
+        }))
+    } else if command == "exportbot"
+        && let Some(bot) = command_input.split_whitespace().nth(1)
+    {
+        Some(Ok(MessageCommand::AIExport {
+            bot: bot.to_string(),
+        }))
+    } else if command == "importbot" {
+        Some(import_persona(command_input))
+    } else if command == "usage" {
+        Some(Ok(MessageCommand::AIUsage))
+    } else if command == "summarize" {
+        Some(summary_range(command_input).map(MessageCommand::Summarize))
+    } else if command == "removebot"
+        && let Some(bot) = command_input.split_whitespace().nth(1)
+    {
         Some(Ok(MessageCommand::AIRemove {
-            bot: command_input[10..].to_string(),
+            bot: bot.to_string(),
         }))
     } else {
         Some(Err(MessageParseError::InvalidCommand))
     }
 }
 
+/// Reads `%importbot [<name>] <persona>`, where the persona is JSON as made by
+/// `%exportbot`, optionally in a code block. A name given before it replaces
+/// the one in the persona.
+fn import_persona(
+    command_input: &str,
+) -> Result<MessageCommand, MessageParseError> {
+    let args = command_input.trim_start().strip_prefix("importbot").unwrap();
+    let args = args.trim();
+    let (name, json) = match args.find(['{', '`']) {
+        Some(0) | None => (None, args),
+        Some(start) => (Some(args[..start].trim()), &args[start..]),
+    };
+    let json = json
+        .trim_start_matches('`')
+        .trim_start_matches("json")
+        .trim_end_matches('`');
+    let mut persona: ai::Persona = serde_json::from_str(json)
+        .map_err(MessageParseError::MalformedPersona)?;
+    if let Some(name) = name {
+        persona.name = name.to_string();
+    }
+    Ok(MessageCommand::AIImport { persona })
+}
+
+/// Reads the range of `%summarize [<n>|since <time>]`
+fn summary_range(
+    command_input: &str,
+) -> Result<SummaryRange, MessageParseError> {
+    let args = command_input.trim_start().strip_prefix("summarize").unwrap();
+    let args = args.trim();
+    if args.is_empty() {
+        return Ok(SummaryRange::Last(DEFAULT_SUMMARY_MESSAGES));
+    }
+    if let Some(time) = args.strip_prefix("since") {
+        return parse_time(time.trim(), Utc::now()).map(SummaryRange::Since);
+    }
+    match args.parse::<usize>() {
+        Ok(count) => Ok(SummaryRange::Last(count.min(MAX_SUMMARY_MESSAGES))),
+        Err(_) => Err(MessageParseError::InvalidCommand),
+    }
+}
+
+/// Reads a UTC time given as a date, a date and time, or just a time, which
+/// means its latest occurrence before `now`
+fn parse_time(
+    time: &str,
+    now: DateTime<Utc>,
+) -> Result<DateTime<Utc>, MessageParseError> {
+    if let Ok(time) = NaiveTime::parse_from_str(time, "%H:%M") {
+        let today = now.date_naive().and_time(time).and_utc();
+        return Ok(if today > now {
+            today - TimeDelta::days(1)
+        } else {
+            today
+        });
+    }
+    if let Ok(date_time) = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M")
+    {
+        return Ok(date_time.and_utc());
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        return Ok(date.and_time(NaiveTime::MIN).and_utc());
+    }
+    Err(MessageParseError::InvalidTime(time.to_string()))
+}
+
+/// Option keys accepted before the instructions of `%newbot` and `%editbot`
+const BOT_OPTION_KEYS: &[&str] = &[
+    "lang",
+    "model",
+    "context",
+    "temperature",
+    "max_tokens",
+    "top_p",
+    "stop",
+    "length",
+];
+
+/// Splits the input of a command like `%newbot <name> [options] <text>` into
+/// the bot name, the options and the text
+fn bot_args(command_input: &str) -> (&str, Vec<(&str, &str)>, &str) {
+    let mut words = command_input.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let name = words.next().unwrap_or_default();
+    let after_name = command_input
+        .trim_start()
+        .strip_prefix(command)
+        .unwrap()
+        .trim_start()
+        .strip_prefix(name)
+        .unwrap();
+    let (options, text) = split_options(after_name);
+    (name, options, text)
+}
+
+fn option(options: &[(&str, &str)], key: &str) -> Option<String> {
+    options
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v.to_string())
+}
+
+/// Reads an option that has to be parsed into something other than text
+fn parsed_option<T: std::str::FromStr>(
+    options: &[(&str, &str)],
+    key: &str,
+) -> Result<Option<T>, MessageParseError> {
+    option(options, key)
+        .map(|value| {
+            value.parse().map_err(|_| MessageParseError::InvalidOption {
+                key: key.to_string(),
+                value,
+            })
+        })
+        .transpose()
+}
+
+/// Reads the bot settings given as options, leaving out the instructions
+fn bot_settings(
+    options: &[(&str, &str)],
+) -> Result<ai::BotSettings, MessageParseError> {
+    // Stop sequences are separated by '|', and can't contain spaces since
+    // options end at whitespace, but "\n" stands for a line break
+    let stop = option(options, "stop").map(|value| {
+        value
+            .split('|')
+            .map(|sequence| sequence.replace("\\n", "\n"))
+            .collect()
+    });
+    Ok(ai::BotSettings {
+        custom_config: None,
+        language: option(options, "lang"),
+        model: option(options, "model"),
+        context_messages: parsed_option(options, "context")?,
+        generation: ai::GenerationParams {
+            temperature: parsed_option(options, "temperature")?,
+            max_tokens: parsed_option(options, "max_tokens")?,
+            top_p: parsed_option(options, "top_p")?,
+            stop,
+            length: parsed_option(options, "length")?,
+        },
+    })
+}
+
+/// Splits leading `key=value` words with a known key off the start of `input`,
+/// returning them along with the rest of the text
+fn split_options(input: &str) -> (Vec<(&str, &str)>, &str) {
+    let mut options = vec![];
+    let mut rest = input.trim_start();
+    while let Some(word) = rest.split_whitespace().next()
+        && let Some((key, value)) = word.split_once('=')
+        && BOT_OPTION_KEYS.contains(&key)
+    {
+        options.push((key, value));
+        rest = rest[word.len()..].trim_start();
+    }
+    (options, rest)
+}
+
 const HELP_MESSAGE: &str = "Valid commands:
 - %ai <message> - ask a question to the default bot (greg)
 - %ask <bot> <message> - ask a question to a bot by name
-- %newbot <name> [lang=<language>] <instructions> - create a new
-bot that follows custom instructions
+- @<bot> anywhere in a message - have a bot reply to the message
+- %newbot <name> [<option>=<value>...] <instructions> - create a new bot that
+follows custom instructions (up to 2000 characters). Options are:
+  - lang=<language>
+  - model=<model> - use a specific model
+  - context=<n> - see the last n messages of the chat
+  - length=short|medium|long - how long replies are
+  - temperature=<0 to 2>, top_p=<0 to 1>, max_tokens=<n> - how replies are
+  generated
+  - stop=<text>[|<text>...] - end replies at any of up to 4 texts, where \\n is
+  a line break
+- %editbot <name> [<option>=<value>...] [<instructions>] - change the settings
+of a bot you created
 - %listbots - list bots by name
 - %removebot <bot> - remove a bot (you can only remove a bot you created)
+- %resetbot <bot> [all] - make a bot forget its conversation with you, or with
+everyone when given all (only for bots you created)
+- %exportbot <bot> - show a bot's settings as JSON that can be imported on
+another server
+- %importbot [<name>] <json> - create a bot from exported settings, optionally
+under a different name
+- %usage - show how many tokens you and the bots have used
+- %summarize [<n>|since <time>] - have the default bot summarize the last n
+messages (50 by default) or the messages since a UTC time like 09:00 or
+2025-01-31 09:00
 - %help - show this message";
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicU64, Arc};
+
+    use async_openai::types::Stop;
+
+    use crate::{
+        ai::{AiContext, HistoryBudget},
+        history::MessageHistory,
+        mock_llm::ScriptedBackend,
+        tools::ToolRegistry,
+        usage::UsageLimits,
+    };
+
+    use super::*;
+
+    /// Stands in for the server state, keeping whatever is posted to it
+    async fn fake_server(mut state_rx: mpsc::Receiver<ServerStateMessage>) {
+        let mut history = MessageHistory::new(1000);
+        let mut next_id = 0;
+        while let Some(msg) = state_rx.recv().await {
+            match msg {
+                ServerStateMessage::NewMessage { mut message } => {
+                    message.id = next_id;
+                    next_id += 1;
+                    message.message_html_safe =
+                        Some(message.message_md.clone());
+                    history.push(message);
+                }
+                ServerStateMessage::StreamStarted { mut message, id_tx } => {
+                    message.id = next_id;
+                    next_id += 1;
+                    let _ = id_tx.send(message.id);
+                    history.push(message);
+                }
+                ServerStateMessage::StreamFinished { id, message_md } => {
+                    if let Some(message) = history.get_mut(id) {
+                        message.message_html_safe = Some(message_md.clone());
+                        message.message_md = message_md;
+                    }
+                }
+                ServerStateMessage::RecentMessages {
+                    count,
+                    since,
+                    chat_only,
+                    messages_tx,
+                } => {
+                    let _ = messages_tx
+                        .send(history.recent(count, since, chat_only));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// A chat whose bots answer with `script`, along with the backend so
+    /// that the requests it got can be checked
+    fn test_chat(script: &[&str]) -> (AppState, Arc<ScriptedBackend>) {
+        test_chat_with_limits(script, UsageLimits::default())
+    }
+
+    fn test_chat_with_limits(
+        script: &[&str],
+        usage_limits: UsageLimits,
+    ) -> (AppState, Arc<ScriptedBackend>) {
+        let (state_tx, state_rx) = mpsc::channel(64);
+        tokio::spawn(fake_server(state_rx));
+        let backend = Arc::new(ScriptedBackend::new(
+            script.iter().map(|reply| reply.to_string()).collect(),
+        ));
+        let ai_context = AiContext::new(
+            None,
+            HistoryBudget {
+                max_chars: 10_000,
+                summarize: false,
+            },
+            None,
+            None,
+            vec![],
+            ToolRegistry::new(state_tx.clone()),
+            usage_limits,
+        )
+        .with_backend(backend.clone());
+        let state = AppState {
+            state_broadcast_tx: tokio::sync::broadcast::channel(8).0,
+            state_tx,
+            ai_context: Arc::new(ai_context),
+            next_connection_id: Arc::new(AtomicU64::new(0)),
+        };
+        (state, backend)
+    }
+
+    /// Has `user` say `text`, returning what the server and bots posted in
+    /// response as `(sender, text)`
+    async fn say(
+        state: &AppState,
+        user: &str,
+        text: &str,
+    ) -> Vec<(String, String)> {
+        let before = recent_messages(state, usize::MAX, None).await.len();
+        let message = new_message(text.to_string(), user.to_string(), None);
+        react_to_message(message, state.clone()).await;
+        // The fake server answers in order, so everything posted has been
+        // handled by the time this arrives
+        recent_messages(state, usize::MAX, None)
+            .await
+            .into_iter()
+            .skip(before)
+            .map(|message| (message.sender, message.message_md))
+            .collect()
+    }
+
+    fn posted(sender: &str, text: &str) -> (String, String) {
+        (sender.to_string(), text.to_string())
+    }
+
+    #[tokio::test]
+    async fn ai_query_is_answered_by_the_default_bot() {
+        let (state, backend) = test_chat(&["Hello alice"]);
+
+        let replies = say(&state, "alice", "%ai hi there").await;
+
+        assert_eq!(replies, [posted("Greg (Bot)", "Hello alice")]);
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 1);
+        let question = requests[0].messages.last().unwrap();
+        assert!(ai::message_text(question).unwrap().contains("hi there"));
+    }
+
+    #[tokio::test]
+    async fn created_bots_can_be_asked_and_removed() {
+        let (state, backend) = test_chat(&["Short answer"]);
+
+        let replies = say(&state, "alice", "%newbot helper Be brief.").await;
+        assert_eq!(replies, [posted("System", "Bot helper created")]);
+
+        let replies = say(&state, "alice", "%ask helper what's up").await;
+        assert_eq!(replies, [posted("helper (Bot)", "Short answer")]);
+
+        let replies = say(&state, "alice", "%removebot helper").await;
+        assert_eq!(replies, [posted("System", "Bot helper removed")]);
+
+        let replies = say(&state, "alice", "%ask helper are you there").await;
+        assert_eq!(
+            replies,
+            [posted(
+                "System",
+                "Bot could not respond:\nBot \"helper\" does not exist"
+            )]
+        );
+        assert_eq!(backend.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn asking_a_missing_bot_is_an_error() {
+        let (state, backend) = test_chat(&[]);
+
+        let replies = say(&state, "alice", "%ask nobody hello there").await;
+
+        assert_eq!(
+            replies,
+            [posted(
+                "System",
+                "Bot could not respond:\nBot \"nobody\" does not exist"
+            )]
+        );
+        assert!(backend.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_can_remove_a_bot() {
+        let (state, _backend) = test_chat(&[]);
+        say(&state, "alice", "%newbot helper Be brief.").await;
+
+        let replies = say(&state, "bob", "%removebot helper").await;
+
+        assert_eq!(
+            replies,
+            [posted(
+                "System",
+                "Could not remove bot:\n\
+                Only alice or an admin can change bot \"helper\""
+            )]
+        );
+        let replies = say(&state, "alice", "%listbots").await;
+        assert!(replies[0].1.contains("- helper"));
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_rate_limit_are_refused() {
+        let limits = UsageLimits {
+            requests_per_minute: Some(1),
+            daily_tokens: None,
+        };
+        let (state, backend) = test_chat_with_limits(&["One", "Two"], limits);
+
+        let replies = say(&state, "alice", "%ai first").await;
+        assert_eq!(replies, [posted("Greg (Bot)", "One")]);
+
+        let replies = say(&state, "alice", "%ai second").await;
+        assert_eq!(replies.len(), 1);
+        let (sender, text) = &replies[0];
+        assert_eq!(sender, "System");
+        assert!(text.starts_with("Bot could not respond:\nToo many requests"));
+        assert_eq!(backend.requests().len(), 1);
+
+        // The limit is per user
+        let replies = say(&state, "bob", "%ai third").await;
+        assert_eq!(replies, [posted("Greg (Bot)", "Two")]);
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_daily_budget_are_refused() {
+        let limits = UsageLimits {
+            requests_per_minute: None,
+            daily_tokens: Some(1),
+        };
+        let (state, backend) = test_chat_with_limits(&["One", "Two"], limits);
+
+        // The budget is only checked before a request, so the first one can
+        // go over it
+        let replies = say(&state, "alice", "%ai first").await;
+        assert_eq!(replies, [posted("Greg (Bot)", "One")]);
+
+        let replies = say(&state, "alice", "%ai second").await;
+        assert_eq!(
+            replies,
+            [posted(
+                "System",
+                "Bot could not respond:\n\
+                Daily budget of 1 tokens used up, try again tomorrow"
+            )]
+        );
+        assert_eq!(backend.requests().len(), 1);
+
+        // The budget is per user
+        let replies = say(&state, "bob", "%ai third").await;
+        assert_eq!(replies, [posted("Greg (Bot)", "Two")]);
+    }
+
+    #[tokio::test]
+    async fn asking_a_missing_bot_does_not_count_as_a_request() {
+        let limits = UsageLimits {
+            requests_per_minute: Some(1),
+            daily_tokens: None,
+        };
+        let (state, _) = test_chat_with_limits(&["Hello"], limits);
+
+        say(&state, "alice", "%ask nobody hello").await;
+        let replies = say(&state, "alice", "%ai hello").await;
+        assert_eq!(replies, [posted("Greg (Bot)", "Hello")]);
+    }
+
+    #[tokio::test]
+    async fn oversized_bot_config_is_refused() {
+        let (state, backend) = test_chat(&[]);
+        let command = format!("%newbot chatty {}", "Talk a lot. ".repeat(1000));
+
+        let replies = say(&state, "alice", &command).await;
+        assert_eq!(replies.len(), 1);
+        let (sender, text) = &replies[0];
+        assert_eq!(sender, "System");
+        assert!(text.starts_with("Could not create bot:\nBot instructions"));
+
+        // Nothing of the config ever reaches the model
+        say(&state, "alice", "%ask chatty hello there").await;
+        assert!(backend.requests().is_empty());
+    }
+
+    #[test]
+    fn mentions_are_found_in_order() {
+        assert_eq!(
+            mentions("@greg, what do you and @helper-2 think? (@Sam)"),
+            ["greg", "helper-2", "Sam"]
+        );
+        assert_eq!(mentions("@greg @greg"), ["greg", "greg"]);
+    }
+
+    #[test]
+    fn at_signs_inside_words_are_not_mentions() {
+        assert!(mentions("mail alice@example.com").is_empty());
+        assert!(mentions("a lone @ sign, and @!").is_empty());
+    }
+
+    #[tokio::test]
+    async fn mentioned_bots_reply_ignoring_case() {
+        let (state, _backend) = test_chat(&["Hi!"]);
+
+        let replies = say(&state, "alice", "hey @GREG").await;
+
+        assert_eq!(replies, [posted("Greg (Bot)", "Hi!")]);
+    }
+
+    #[tokio::test]
+    async fn bots_mentioned_twice_reply_once() {
+        let (state, backend) = test_chat(&["Hi!"]);
+
+        let replies = say(&state, "alice", "@greg are you there, @Greg?").await;
+
+        assert_eq!(replies, [posted("Greg (Bot)", "Hi!")]);
+        assert_eq!(backend.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn mentions_of_people_are_ignored() {
+        let (state, backend) = test_chat(&[]);
+
+        let replies = say(&state, "alice", "thanks @bob").await;
+
+        assert!(replies.is_empty());
+        assert!(backend.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_a_few_mentioned_bots_reply() {
+        let (state, backend) = test_chat(&[]);
+        for name in ["one", "two", "three"] {
+            say(&state, "alice", &format!("%newbot {name} Be brief.")).await;
+        }
+
+        let replies = say(&state, "alice", "@greg @one @two @three hi").await;
+
+        let senders: Vec<&str> =
+            replies.iter().map(|(sender, _)| sender.as_str()).collect();
+        assert_eq!(senders, ["Greg (Bot)", "one (Bot)", "two (Bot)"]);
+        assert_eq!(backend.requests().len(), MAX_MENTIONED_BOTS);
+    }
+
+    fn at(date_time: &str) -> DateTime<Utc> {
+        NaiveDateTime::parse_from_str(date_time, "%Y-%m-%d %H:%M")
+            .unwrap()
+            .and_utc()
+    }
+
+    #[test]
+    fn times_of_day_are_their_latest_occurrence() {
+        let now = at("2025-01-31 12:00");
+        assert_eq!(parse_time("09:30", now).unwrap(), at("2025-01-31 09:30"));
+        assert_eq!(parse_time("12:00", now).unwrap(), now);
+        assert_eq!(parse_time("18:00", now).unwrap(), at("2025-01-30 18:00"));
+    }
+
+    #[test]
+    fn dates_are_read_with_or_without_a_time() {
+        let now = at("2025-01-31 12:00");
+        assert_eq!(
+            parse_time("2025-01-15 08:05", now).unwrap(),
+            at("2025-01-15 08:05")
+        );
+        assert_eq!(
+            parse_time("2025-01-15", now).unwrap(),
+            at("2025-01-15 00:00")
+        );
+    }
+
+    #[test]
+    fn invalid_times_are_errors() {
+        let now = at("2025-01-31 12:00");
+        for time in ["", "noon", "25:00", "2025-02-30", "9.30"] {
+            assert!(
+                matches!(
+                    parse_time(time, now),
+                    Err(MessageParseError::InvalidTime(ref t)) if t == time
+                ),
+                "{time:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn summaries_cover_the_latest_messages_by_default() {
+        assert!(matches!(
+            summary_range("summarize"),
+            Ok(SummaryRange::Last(DEFAULT_SUMMARY_MESSAGES))
+        ));
+        assert!(matches!(
+            summary_range("summarize 20"),
+            Ok(SummaryRange::Last(20))
+        ));
+        assert!(matches!(
+            summary_range("summarize 100000"),
+            Ok(SummaryRange::Last(MAX_SUMMARY_MESSAGES))
+        ));
+    }
+
+    #[test]
+    fn summaries_can_start_at_a_time() {
+        assert!(matches!(
+            summary_range("summarize since 2025-01-15 08:05"),
+            Ok(SummaryRange::Since(since)) if since == at("2025-01-15 08:05")
+        ));
+        assert!(matches!(
+            summary_range("summarize since yesterday"),
+            Err(MessageParseError::InvalidTime(_))
+        ));
+        assert!(matches!(
+            summary_range("summarize everything"),
+            Err(MessageParseError::InvalidCommand)
+        ));
+    }
+
+    /// The persona `%importbot` reads from `command_input`
+    fn imported(command_input: &str) -> ai::Persona {
+        match import_persona(command_input) {
+            Ok(MessageCommand::AIImport { persona }) => persona,
+            Ok(_) => panic!("not an import"),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    const PERSONA: &str = r#"{
+  "name": "helper",
+  "custom_config": "Be brief.",
+  "language": "English",
+  "temperature": 0.5
+}"#;
+
+    #[test]
+    fn personas_can_be_imported_from_code_blocks() {
+        for input in [
+            format!("importbot {PERSONA}"),
+            format!("importbot ```{PERSONA}```"),
+            format!("importbot ```json\n{PERSONA}\n```"),
+        ] {
+            let persona = imported(&input);
+            assert_eq!(persona.name, "helper");
+            assert_eq!(persona.custom_config, "Be brief.");
+            assert_eq!(persona.generation.temperature, Some(0.5));
+        }
+    }
+
+    #[test]
+    fn names_given_on_import_replace_the_persona_name() {
+        let persona = imported(&format!("importbot copy {PERSONA}"));
+        assert_eq!(persona.name, "copy");
+        let persona =
+            imported(&format!("importbot copy ```json\n{PERSONA}```"));
+        assert_eq!(persona.name, "copy");
+    }
+
+    #[test]
+    fn malformed_personas_are_refused() {
+        for input in [
+            "importbot",
+            "importbot {",
+            "importbot ```json\n{\"name\": \"helper\"}\n```",
+            "importbot {\"name\": \"helper\", \"custom_config\": 3}",
+        ] {
+            assert!(
+                matches!(
+                    import_persona(input),
+                    Err(MessageParseError::MalformedPersona(_))
+                ),
+                "{input:?} was accepted"
+            );
+        }
+    }
+
+    /// The JSON of the persona in an `%exportbot` reply
+    fn exported_json(reply: &str) -> &str {
+        let start = reply.find("```json\n").unwrap() + "```json\n".len();
+        let end = reply.rfind("\n```").unwrap();
+        &reply[start..end]
+    }
+
+    #[tokio::test]
+    async fn exported_bots_import_with_the_same_settings() {
+        let (state, backend) = test_chat(&[]);
+        say(
+            &state,
+            "alice",
+            "%newbot helper lang=French temperature=0.5 top_p=0.9 \
+            max_tokens=200 stop=END|FIN length=short Be brief.",
+        )
+        .await;
+        let replies = say(&state, "alice", "%exportbot helper").await;
+        let exported = exported_json(&replies[0].1).to_string();
+
+        // Pasted as the code block it was exported in
+        let command = format!("%importbot copy ```json\n{exported}\n```");
+        let replies = say(&state, "bob", &command).await;
+        assert_eq!(replies, [posted("System", "Bot copy imported")]);
+
+        let replies = say(&state, "bob", "%exportbot copy").await;
+        let mut original: serde_json::Value =
+            serde_json::from_str(&exported).unwrap();
+        original["name"] = "copy".into();
+        let copy: serde_json::Value =
+            serde_json::from_str(exported_json(&replies[0].1)).unwrap();
+        assert_eq!(copy, original);
+
+        // The settings are used, not just kept
+        say(&state, "bob", "%ask copy hello").await;
+        let request = backend.requests().pop().unwrap();
+        assert_eq!(request.temperature, Some(0.5));
+        assert_eq!(request.top_p, Some(0.9));
+        assert_eq!(request.max_completion_tokens, Some(200));
+        assert!(matches!(
+            request.stop,
+            Some(Stop::StringArray(ref stop)) if stop == &["END", "FIN"]
+        ));
+    }
+
+    /// Whether the last request the bots made includes `text`
+    fn last_request_mentions(backend: &ScriptedBackend, text: &str) -> bool {
+        let request = backend.requests().pop().unwrap();
+        request
+            .messages
+            .iter()
+            .filter_map(ai::message_text)
+            .any(|message| message.contains(text))
+    }
+
+    #[tokio::test]
+    async fn conversations_are_kept_apart_per_user() {
+        let (state, backend) = test_chat(&[]);
+        say(&state, "alice", "%ai my favourite colour is teal").await;
+
+        say(&state, "bob", "%ai what is my favourite colour?").await;
+        assert!(!last_request_mentions(&backend, "teal"));
+
+        say(&state, "alice", "%ai what is my favourite colour?").await;
+        assert!(last_request_mentions(&backend, "teal"));
+    }
+
+    #[tokio::test]
+    async fn resetting_a_bot_forgets_only_the_senders_conversation() {
+        let (state, backend) = test_chat(&[]);
+        say(&state, "alice", "%ai my favourite colour is teal").await;
+        say(&state, "bob", "%ai my favourite colour is red").await;
+
+        let replies = say(&state, "bob", "%resetbot greg").await;
+        assert_eq!(
+            replies,
+            [posted("System", "Bot Greg forgot its conversation with you")]
+        );
+
+        say(&state, "bob", "%ai what is my favourite colour?").await;
+        assert!(!last_request_mentions(&backend, "red"));
+        say(&state, "alice", "%ai what is my favourite colour?").await;
+        assert!(last_request_mentions(&backend, "teal"));
+    }
+
+    #[tokio::test]
+    async fn only_creators_can_reset_everyones_conversations() {
+        let (state, backend) = test_chat(&[]);
+        say(&state, "alice", "%newbot helper Be brief.").await;
+        say(&state, "bob", "%ask helper my favourite colour is red").await;
+
+        let replies = say(&state, "bob", "%resetbot helper all").await;
+        assert_eq!(replies.len(), 1);
+        assert!(replies[0].1.starts_with("Could not reset bot:\n"));
+
+        let replies = say(&state, "alice", "%resetbot helper all").await;
+        assert_eq!(
+            replies,
+            [posted(
+                "System",
+                "Bot helper forgot its conversations with everyone"
+            )]
+        );
+        say(&state, "bob", "%ask helper what is my favourite colour?").await;
+        assert!(!last_request_mentions(&backend, "red"));
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+use rss_chat::socket::{ReadWatermark, UserMessage};
+
+/// Recent messages and how far each user has read, kept in memory so that new
+/// connections can catch up
+pub struct MessageHistory {
+    messages: VecDeque<UserMessage>,
+    capacity: usize,
+    /// Highest message id each user has actually seen
+    read_watermarks: HashMap<String, u32>,
+}
+
+impl MessageHistory {
+    pub fn new(capacity: usize) -> MessageHistory {
+        MessageHistory {
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+            read_watermarks: HashMap::new(),
+        }
+    }
+    /// Adds a message, dropping the oldest one if the history is full
+    pub fn push(&mut self, message: UserMessage) {
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut UserMessage> {
+        self.messages.iter_mut().find(|message| message.id == id)
+    }
+    /// Up to `count` of the newest messages that aren't still being written,
+    /// oldest first, leaving out any sent before `since`. With `chat_only`,
+    /// commands and messages from the server are left out too.
+    pub fn recent(
+        &self,
+        count: usize,
+        since: Option<DateTime<Utc>>,
+        chat_only: bool,
+    ) -> Vec<UserMessage> {
+        let mut recent: Vec<UserMessage> = self
+            .messages
+            .iter()
+            .rev()
+            .filter(|message| message.message_html_safe.is_some())
+            .filter(|message| {
+                since.is_none_or(|since| message.send_time >= since)
+            })
+            .filter(|message| !chat_only || is_chat_message(message))
+            .take(count)
+            .cloned()
+            .collect();
+        recent.reverse();
+        recent
+    }
+    /// Up to `limit` of the newest finished messages containing `query`,
+    /// ignoring case, newest first
+    pub fn search(&self, query: &str, limit: usize) -> Vec<UserMessage> {
+        let query = query.to_lowercase();
+        self.messages
+            .iter()
+            .rev()
+            .filter(|message| message.message_html_safe.is_some())
+            .filter(|message| {
+                message.message_md.to_lowercase().contains(&query)
+            })
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+    pub fn messages(&self) -> Vec<UserMessage> {
+        self.messages.iter().cloned().collect()
+    }
+    /// Moves a user's watermark forward to `up_to`, returning whether it moved.
+    /// Watermarks never go backwards or past the newest message.
+    pub fn mark_read(
+        &mut self,
+        user: &str,
+        up_to: u32,
+        latest_id: Option<u32>,
+    ) -> bool {
+        if latest_id.is_none_or(|latest| up_to > latest) {
+            return false;
+        }
+        let watermark = self.read_watermarks.get(user).copied();
+        if watermark.is_some_and(|watermark| watermark >= up_to) {
+            return false;
+        }
+        self.read_watermarks.insert(user.to_string(), up_to);
+        true
+    }
+    pub fn read_watermarks(&self) -> Vec<ReadWatermark> {
+        self.read_watermarks
+            .iter()
+            .map(|(user, up_to)| ReadWatermark {
+                user: user.clone(),
+                up_to: *up_to,
+            })
+            .collect()
+    }
+}
+
+/// Whether a message is something people said to each other, rather than a
+/// command or a message from the server
+fn is_chat_message(message: &UserMessage) -> bool {
+    message.sender != "System" && !message.message_md.starts_with('%')
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn message(id: u32, sender: &str, text: &str, minute: u32) -> UserMessage {
+        UserMessage {
+            send_time: Utc.with_ymd_and_hms(2025, 1, 31, 9, minute, 0).unwrap(),
+            sender: sender.to_string(),
+            message_md: text.to_string(),
+            message_short: None,
+            message_html_safe: Some(text.to_string()),
+            reply_to: None,
+            id,
+        }
+    }
+
+    fn history(messages: Vec<UserMessage>) -> MessageHistory {
+        let mut history = MessageHistory::new(100);
+        for message in messages {
+            history.push(message);
+        }
+        history
+    }
+
+    fn ids(messages: &[UserMessage]) -> Vec<u32> {
+        messages.iter().map(|message| message.id).collect()
+    }
+
+    #[test]
+    fn recent_messages_are_oldest_first() {
+        let history = history(vec![
+            message(0, "alice", "one", 0),
+            message(1, "bob", "two", 1),
+            message(2, "alice", "three", 2),
+        ]);
+        assert_eq!(ids(&history.recent(2, None, false)), [1, 2]);
+        assert_eq!(ids(&history.recent(10, None, false)), [0, 1, 2]);
+    }
+
+    #[test]
+    fn messages_being_written_are_left_out() {
+        let mut streaming = message(1, "Greg (Bot)", "", 1);
+        streaming.message_html_safe = None;
+        let history = history(vec![
+            message(0, "alice", "one", 0),
+            streaming,
+            message(2, "alice", "two", 2),
+        ]);
+        assert_eq!(ids(&history.recent(10, None, false)), [0, 2]);
+    }
+
+    #[test]
+    fn older_messages_are_left_out_wherever_they_are() {
+        // A message out of order doesn't hide the ones before it
+        let history = history(vec![
+            message(0, "alice", "one", 5),
+            message(1, "bob", "two", 6),
+            message(2, "alice", "three", 1),
+            message(3, "bob", "four", 7),
+        ]);
+        let since = Utc.with_ymd_and_hms(2025, 1, 31, 9, 5, 0).unwrap();
+        assert_eq!(ids(&history.recent(10, Some(since), false)), [0, 1, 3]);
+    }
+
+    #[test]
+    fn count_applies_to_chat_messages_only() {
+        let history = history(vec![
+            message(0, "alice", "one", 0),
+            message(1, "bob", "two", 1),
+            message(2, "alice", "%summarize", 2),
+            message(3, "System", "Summary", 3),
+            message(4, "bob", "three", 4),
+        ]);
+        assert_eq!(ids(&history.recent(2, None, false)), [3, 4]);
+        assert_eq!(ids(&history.recent(2, None, true)), [1, 4]);
+    }
+}
@@ -6,7 +6,10 @@
 // - [ ] Add message editing
 
 use cfg_if::cfg_if;
-use rss_chat::socket::{ServerMessage, UserMessage, VisibilityState};
+use rss_chat::socket::{
+    CustomStatus, ServerMessage, UserCustomStatus, UserMessage, UserStatus,
+    UserStatusInfo, VisibilityState,
+};
 
 cfg_if! {
     if #[cfg(feature = "ssr")] {
@@ -17,26 +20,116 @@ cfg_if! {
             routing::get,
             Router,
         };
-        use futures::stream::{SplitSink, SplitStream};
+        use futures::{Sink, Stream};
+        use std::path::PathBuf;
         use std::time::Duration;
-        use std::sync::{Arc, Mutex};
+        use std::sync::{atomic::AtomicU64, Arc};
 
         mod ai;
-        use ai::AiContext;
+        use ai::{AiConfig, AiContext, HistoryBudget};
+
+        mod backend;
 
         mod commands;
+
+        mod history;
+        use history::MessageHistory;
+
+        mod mock_llm;
+
+        mod persist;
+
+        mod presence;
+        use presence::{
+            ConnectionId, LastActive, SessionRegistry, UserPresence,
+        };
+
+        mod tools;
+        use tools::ToolRegistry;
+
+        mod usage;
+        use usage::UsageLimits;
     }
 }
 
 #[cfg(feature = "ssr")]
 #[derive(Debug)]
 enum ServerStateMessage {
-    UserJoined { name: String },
-    UserDisconnected { name: String },
-    UserTyped { name: String },
-    NewMessage { message: UserMessage },
-    UserReadMessages { user: String, earliest: u32 },
-    VisbilityUpdate { user: String, vis: VisibilityState },
+    UserJoined {
+        conn: ConnectionId,
+        name: String,
+        direct_tx: tokio::sync::mpsc::Sender<ServerMessage>,
+    },
+    UserDisconnected {
+        conn: ConnectionId,
+    },
+    UserTyped {
+        conn: ConnectionId,
+    },
+    TypingExpired {
+        conn: ConnectionId,
+        generation: u64,
+    },
+    NewMessage {
+        message: UserMessage,
+    },
+    /// Posts a message whose text will arrive in pieces, replying with the id
+    /// it was given
+    StreamStarted {
+        message: UserMessage,
+        id_tx: tokio::sync::oneshot::Sender<u32>,
+    },
+    StreamDelta {
+        id: u32,
+        delta: String,
+    },
+    /// Replaces the streamed text of a message with its final version
+    StreamFinished {
+        id: u32,
+        message_md: String,
+    },
+    /// Asks for the latest finished messages, for bots that see or summarize
+    /// the chat
+    RecentMessages {
+        count: usize,
+        since: Option<chrono::DateTime<chrono::Utc>>,
+        /// Whether to leave out commands and messages from the server
+        chat_only: bool,
+        messages_tx: tokio::sync::oneshot::Sender<Vec<UserMessage>>,
+    },
+    /// Asks for messages containing some text, for bots searching the chat
+    SearchMessages {
+        query: String,
+        limit: usize,
+        messages_tx: tokio::sync::oneshot::Sender<Vec<UserMessage>>,
+    },
+    /// Asks for the statuses of users that are online
+    OnlineUsers {
+        users_tx: tokio::sync::oneshot::Sender<Vec<UserStatusInfo>>,
+    },
+    UserReadMessages {
+        user: String,
+        up_to: u32,
+    },
+    VisbilityUpdate {
+        conn: ConnectionId,
+        vis: VisibilityState,
+    },
+    CustomStatusSet {
+        conn: ConnectionId,
+        status: Option<CustomStatus>,
+    },
+    /// Sent periodically to notice users becoming idle and to save presence
+    PresenceTick,
+    /// The connection missed broadcasts and needs the current state of the
+    /// chat again
+    Resync {
+        conn: ConnectionId,
+    },
+    /// The last seen times as of `version` were written to disk
+    LastSeenSaved {
+        version: u64,
+    },
 }
 
 #[cfg(feature = "ssr")]
@@ -45,13 +138,12 @@ struct AppStateExt {
     state_broadcast_tx: tokio::sync::broadcast::Sender<ServerMessage>,
     state_tx: tokio::sync::mpsc::Sender<ServerStateMessage>,
     ai_context: Arc<AiContext>,
+    next_connection_id: Arc<AtomicU64>,
 }
 
 #[cfg(feature = "ssr")]
 #[tokio::main]
 async fn main() {
-    use std::collections::HashMap;
-
     use leptos::logging::log;
     use leptos::prelude::*;
     use leptos_axum::{generate_route_list, LeptosRoutes};
@@ -59,10 +151,14 @@ async fn main() {
 
     env_logger::init();
 
-    const BROADCAST_CAPACITY: usize = 8;
+    // Streaming bot replies broadcast a delta every few hundred milliseconds,
+    // so this leaves room for a slow client to catch up
+    const BROADCAST_CAPACITY: usize = 256;
     const STATE_CHANNEL_CAPACITY: usize = 8;
 
     const TYPING_TIME: Duration = Duration::from_millis(1500);
+    const PRESENCE_TICK_INTERVAL: Duration = Duration::from_secs(30);
+    const HISTORY_CAPACITY: usize = 500;
 
     let conf = get_configuration(None).unwrap();
     let addr = conf.leptos_options.site_addr;
@@ -75,188 +171,432 @@ async fn main() {
     let (state_tx, mut state_rx) =
         tokio::sync::mpsc::channel(STATE_CHANNEL_CAPACITY);
 
+    if let Ok(addr) = std::env::var("AI_MOCK_SERVER_ADDR") {
+        match addr.parse() {
+            Ok(addr) => {
+                tokio::spawn(mock_llm::serve(addr));
+            }
+            Err(e) => {
+                log::error!("Invalid AI_MOCK_SERVER_ADDR \"{addr}\":\n{e}")
+            }
+        }
+    }
+    let ai_config = AiConfig::from_env();
+    match ai_config {
+        Some(ref config) => log::info!(
+            "Using AI provider at {} with model {}",
+            config.api_base,
+            config.default_model
+        ),
+        None => log::warn!("No AI provider configured, bots are disabled"),
+    }
+    let history_budget = HistoryBudget::from_env();
+    log::info!(
+        "Keeping up to {} characters of bot history{}",
+        history_budget.max_chars,
+        if history_budget.summarize {
+            ", summarizing older turns"
+        } else {
+            ""
+        }
+    );
+    let bot_save_path = std::env::var("BOT_SAVE_PATH").ok().map(PathBuf::from);
+    let saved_bots = match bot_save_path {
+        Some(ref path) => match persist::load_json(path).await {
+            Ok(bots) => bots,
+            Err(e) => {
+                log::error!("Failed reading {}:\n{e}", path.display());
+                return;
+            }
+        },
+        None => None,
+    };
+    // Comma separated names of users who may change or remove any bot
+    let bot_admins = std::env::var("BOT_ADMINS")
+        .map(|admins| {
+            admins
+                .split(',')
+                .map(str::trim)
+                .filter(|admin| !admin.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    let usage_limits = UsageLimits::from_env();
+    if let Some(requests) = usage_limits.requests_per_minute {
+        log::info!("Limiting users to {requests} bot requests per minute");
+    }
+    if let Some(tokens) = usage_limits.daily_tokens {
+        log::info!("Limiting users to {tokens} bot tokens per day");
+    }
     let ai_context = AiContext::new(
-        &std::env::var("GROQ_API_KEY").expect("No api key provided"),
+        ai_config,
+        history_budget,
+        saved_bots,
+        bot_save_path,
+        bot_admins,
+        ToolRegistry::new(state_tx.clone()),
+        usage_limits,
     );
 
     let app_state = AppStateExt {
         state_broadcast_tx: state_broadcast_tx.clone(),
         state_tx,
         ai_context: Arc::new(ai_context),
+        next_connection_id: Arc::new(AtomicU64::new(0)),
+    };
+
+    if let Ok(time) = std::env::var("AI_DAILY_DIGEST_TIME") {
+        match chrono::NaiveTime::parse_from_str(&time, "%H:%M") {
+            Ok(at) => {
+                log::info!("Posting a daily digest at {at} UTC");
+                tokio::spawn(commands::post_daily_digests(
+                    app_state.clone(),
+                    at,
+                ));
+            }
+            Err(e) => {
+                log::error!("Invalid AI_DAILY_DIGEST_TIME \"{time}\":\n{e}")
+            }
+        }
+    }
+
+    let last_seen_path =
+        std::env::var("LAST_SEEN_SAVE_PATH").ok().map(PathBuf::from);
+    let mut sessions = match last_seen_path {
+        Some(ref path) => match persist::load_json(path).await {
+            Ok(last_active) => SessionRegistry::with_last_active(
+                last_active.unwrap_or_default(),
+            ),
+            Err(e) => {
+                log::error!("Failed reading {}:\n{e}", path.display());
+                return;
+            }
+        },
+        None => SessionRegistry::default(),
     };
+    // Last seen times are written by a task of their own, so that a slow
+    // disk doesn't hold up the state loop. Saves that fail are retried on
+    // the next tick.
+    let last_seen_save_tx = last_seen_path.map(|path| {
+        let (save_tx, mut save_rx) =
+            tokio::sync::mpsc::channel::<(u64, LastActive)>(1);
+        let state_tx = app_state.state_tx.clone();
+        tokio::spawn(async move {
+            while let Some((version, last_active)) = save_rx.recv().await {
+                if let Err(e) = persist::save_json(&path, &last_active).await {
+                    log::error!("Failed saving last seen times:\n{e}");
+                    continue;
+                }
+                let _ = state_tx
+                    .send(ServerStateMessage::LastSeenSaved { version })
+                    .await;
+            }
+        });
+        save_tx
+    });
+
+    {
+        let state_tx = app_state.state_tx.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRESENCE_TICK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if state_tx
+                    .send(ServerStateMessage::PresenceTick)
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+    }
 
     let app_state_2 = app_state.clone();
     tokio::spawn(async move {
-        let typing_counters: Arc<Mutex<Vec<(String, u64)>>> =
-            Arc::new(Mutex::new(vec![]));
-        let typing_users: Arc<Mutex<Vec<String>>> =
-            Arc::new(Mutex::new(vec![]));
         let state_broadcast_tx = state_broadcast_tx.clone();
-        let mut current_message_id = 0;
-        let mut viewing_users: Vec<String> = Vec::new();
-
-        let mut online_users: HashMap<String, u8> = HashMap::new();
+        let mut current_message_id: u32 = 0;
+        let mut history = MessageHistory::new(HISTORY_CAPACITY);
 
         let send_msg = move |msg: ServerMessage| {
             if let Err(e) = state_broadcast_tx.send(msg) {
                 log::error!("Error when sending state broadcast message:\n{e}");
             }
         };
+        // Broadcasts whatever changed about a user's aggregated presence
+        let send_presence_diff = {
+            let send_msg = send_msg.clone();
+            move |sessions: &SessionRegistry,
+                  user: &str,
+                  before: UserPresence| {
+                let after = sessions.presence(user);
+                if before.online != after.online {
+                    send_msg(ServerMessage::OnlineUsersUpdate {
+                        users: sessions.online_users(),
+                    });
+                }
+                if before.typing != after.typing {
+                    let user = user.to_string();
+                    send_msg(if after.typing {
+                        ServerMessage::UserTyping { user }
+                    } else {
+                        ServerMessage::UserStoppedTyping { user }
+                    });
+                }
+                if before.observing != after.observing {
+                    let user = user.to_string();
+                    send_msg(if after.observing {
+                        ServerMessage::UserObserving { user }
+                    } else {
+                        ServerMessage::UserNotObserving { user }
+                    });
+                }
+            }
+        };
+
+        // What a connection needs to catch up with the chat, sent when it
+        // joins or after it fell behind. Messages still being streamed are
+        // included with the text they have so far.
+        let send_snapshot = |sessions: &SessionRegistry,
+                             history: &MessageHistory,
+                             conn: ConnectionId| {
+            sessions.send_to(
+                conn,
+                ServerMessage::History {
+                    messages: history.messages(),
+                    read_watermarks: history.read_watermarks(),
+                },
+            );
+            sessions.send_to(
+                conn,
+                ServerMessage::UserStatusUpdate {
+                    statuses: sessions.statuses(chrono::Utc::now()),
+                },
+            );
+            sessions.send_to(
+                conn,
+                ServerMessage::CustomStatusUpdate {
+                    statuses: sessions.custom_statuses(),
+                },
+            );
+        };
 
         while let Some(msg) = state_rx.recv().await {
             match msg {
-                ServerStateMessage::UserTyped { ref name } => {
-                    let idx = {
-                        let mut counters = typing_counters.lock().unwrap();
-                        let mut typing_users = typing_users.lock().unwrap();
-
-                        if !typing_users.contains(name) {
-                            typing_users.push(name.clone());
-                            send_msg(ServerMessage::UserTyping {
-                                user: name.clone(),
+                ServerStateMessage::UserTyped { conn } => {
+                    let Some(user) = sessions.user(conn).map(str::to_string)
+                    else {
+                        continue;
+                    };
+                    let before = sessions.presence(&user);
+                    let Some(generation) = sessions.typed(conn) else {
+                        continue;
+                    };
+                    send_presence_diff(&sessions, &user, before);
+
+                    let state_tx = app_state.state_tx.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(TYPING_TIME).await;
+                        let _ = state_tx
+                            .send(ServerStateMessage::TypingExpired {
+                                conn,
+                                generation,
                             })
-                        }
-
-                        if let Some(v) =
-                            counters.iter_mut().find(|i| i.0 == *name)
-                        {
-                            v.1 += 1;
-                            v.1
-                        } else {
-                            counters.push((name.to_string(), 0));
-                            0
-                        }
+                            .await;
+                    });
+                }
+                ServerStateMessage::TypingExpired { conn, generation } => {
+                    let Some(user) = sessions.user(conn).map(str::to_string)
+                    else {
+                        continue;
                     };
+                    let before = sessions.presence(&user);
+                    sessions.typing_expired(conn, generation);
+                    send_presence_diff(&sessions, &user, before);
+                }
+                ServerStateMessage::UserJoined {
+                    conn,
+                    name,
+                    direct_tx,
+                } => {
+                    let before = sessions.presence(&name);
+                    sessions.join(conn, name.clone(), direct_tx);
+                    send_presence_diff(&sessions, &name, before);
+                    send_snapshot(&sessions, &history, conn);
+                }
+                ServerStateMessage::Resync { conn } => {
+                    sessions.send_to(
+                        conn,
+                        ServerMessage::OnlineUsersUpdate {
+                            users: sessions.online_users(),
+                        },
+                    );
+                    send_snapshot(&sessions, &history, conn);
+                }
+                ServerStateMessage::CustomStatusSet { conn, status } => {
+                    let Some(user) = sessions.user(conn).map(str::to_string)
+                    else {
+                        continue;
+                    };
+                    let status = status.map(|mut status| {
+                        status.text = status.text.trim().to_string();
+                        status
+                    });
+                    if let Some(ref status) = status
+                        && !status.is_valid(chrono::Utc::now())
                     {
-                        let name = name.clone();
-                        let typing_users = typing_users.clone();
-                        let typing_counters = typing_counters.clone();
-                        let send_msg = send_msg.clone();
-                        tokio::spawn(async move {
-                            tokio::time::sleep(TYPING_TIME).await;
-                            let counters = typing_counters.lock().unwrap();
-                            let mut typing_users = typing_users.lock().unwrap();
-                            if let Some(v) = counters
-                                .iter()
-                                .find(|i| i.0 == name)
-                                .map(|v| v.1)
-                                && v == idx
-                                && let Some(typing_idx) =
-                                    typing_users.iter().position(|i| *i == name)
-                            {
-                                typing_users.remove(typing_idx);
-                                send_msg(ServerMessage::UserStoppedTyping {
-                                    user: name,
-                                });
-                            }
-                        });
+                        log::info!(
+                            "Rejected invalid custom status from {user}"
+                        );
+                        continue;
                     }
-                }
-                ServerStateMessage::UserJoined { name } => {
-                    *online_users.entry(name).or_default() += 1;
-
-                    send_msg(ServerMessage::OnlineUsersUpdate {
-                        users: online_users
-                            .iter()
-                            .filter(|(_n, i)| **i > 0)
-                            .map(|(n, _i)| n.clone())
-                            .collect(),
+                    sessions.set_custom_status(&user, status.clone());
+                    send_msg(ServerMessage::CustomStatusUpdate {
+                        statuses: vec![UserCustomStatus { user, status }],
                     });
                 }
                 ServerStateMessage::NewMessage { mut message } => {
-                    let original = message.clone();
+                    sessions.touch(&message.sender);
+                    // Clients' clocks can't be trusted to keep the history in
+                    // order
+                    message.send_time = chrono::Utc::now();
                     message.id = current_message_id;
                     current_message_id += 1;
-                    let mut message_html_safe = message
-                        .message_md
-                        .lines()
-                        .map(|line| line.trim_end()) // Trim trailing spaces
-                        .collect::<Vec<_>>() // Collect into a Vec
-                        .join("  \n"); // Join with Markdown's line break syntax (two spaces + newline)
-
-                    // Configure commonmark extensions
-                    let mut comrak_options = comrak::Options::default();
-                    macro_rules! enable_exts {
-                        ($($x:ident),+ $(,)?) => {{
-                            $(
-                                comrak_options.extension.$x = true;
-                            )*
-                        }};
-                    }
-                    enable_exts! {
-                        table,
-                        strikethrough,
-                        autolink,
-
-                        // TODO: Implement CSS for this
-                        alerts,
-
-                        // TODO: Implement mathjax to SVG, then add math things
-
-                        wikilinks_title_after_pipe,
-                        underline,
-                        subscript,
-                        multiline_block_quotes,
-                    };
-                    comrak_options.render.hardbreaks = true;
-                    comrak_options.render.escape = true;
-                    comrak_options.render.ignore_empty_links = true;
-                    comrak_options.parse.smart = true;
-
-                    message_html_safe = comrak::markdown_to_html(
-                        &message_html_safe,
-                        &comrak_options,
-                    );
-                    message.message_html_safe = Some(message_html_safe);
+                    message.message_html_safe =
+                        Some(render_markdown(&message.message_md));
                     message.message_short = Some(message.get_short());
                     log::debug!("Sending message:\n{message:?}");
+                    history.push(message.clone());
                     send_msg(ServerMessage::MessageSent {
                         message: message.clone(),
                     });
 
                     let app_state = app_state.clone();
                     tokio::spawn(async move {
-                        commands::react_to_message(original, app_state).await;
+                        commands::react_to_message(message, app_state).await;
                     });
                 }
-                ServerStateMessage::UserDisconnected { name } => {
-                    let ent = online_users.entry(name.clone()).or_default();
-                    *ent = ent.saturating_sub(1);
-                    if *ent == 0 {
-                        online_users.remove(&name);
-                    }
-                    send_msg(ServerMessage::OnlineUsersUpdate {
-                        users: online_users
-                            .iter()
-                            .filter(|(_n, i)| **i > 0)
-                            .map(|(n, _i)| n.clone())
-                            .collect(),
+                ServerStateMessage::StreamStarted {
+                    mut message,
+                    id_tx,
+                } => {
+                    message.id = current_message_id;
+                    current_message_id += 1;
+                    message.message_html_safe = None;
+                    message.message_short = None;
+                    history.push(message.clone());
+                    send_msg(ServerMessage::MessageSent {
+                        message: message.clone(),
+                    });
+                    send_msg(ServerMessage::UserTyping {
+                        user: message.sender,
                     });
+                    let _ = id_tx.send(message.id);
                 }
-                ServerStateMessage::UserReadMessages { user, earliest } => {
-                    send_msg(ServerMessage::MessagesRead {
-                        by_user: user,
-                        earliest,
+                ServerStateMessage::StreamDelta { id, delta } => {
+                    let Some(message) = history.get_mut(id) else {
+                        continue;
+                    };
+                    message.message_md.push_str(&delta);
+                    send_msg(ServerMessage::MessageStreaming { id, delta });
+                }
+                ServerStateMessage::StreamFinished { id, message_md } => {
+                    let Some(message) = history.get_mut(id) else {
+                        continue;
+                    };
+                    message.message_html_safe =
+                        Some(render_markdown(&message_md));
+                    message.message_md = message_md;
+                    message.message_short = Some(message.get_short());
+                    let message = message.clone();
+                    send_msg(ServerMessage::UserStoppedTyping {
+                        user: message.sender.clone(),
                     });
+                    send_msg(ServerMessage::MessageFinalized { message });
+                }
+                ServerStateMessage::RecentMessages {
+                    count,
+                    since,
+                    chat_only,
+                    messages_tx,
+                } => {
+                    let _ = messages_tx
+                        .send(history.recent(count, since, chat_only));
+                }
+                ServerStateMessage::SearchMessages {
+                    query,
+                    limit,
+                    messages_tx,
+                } => {
+                    let _ = messages_tx.send(history.search(&query, limit));
+                }
+                ServerStateMessage::OnlineUsers { users_tx } => {
+                    let mut users: Vec<UserStatusInfo> = sessions
+                        .statuses(chrono::Utc::now())
+                        .into_iter()
+                        .filter(|info| info.status != UserStatus::Offline)
+                        .collect();
+                    users.sort_by(|a, b| a.user.cmp(&b.user));
+                    let _ = users_tx.send(users);
                 }
-                ServerStateMessage::VisbilityUpdate { user, vis } => {
-                    match vis {
-                        VisibilityState::Hidden => {
-                            if viewing_users.contains(&user) {
-                                viewing_users.retain(|i| *i != user);
-                                send_msg(ServerMessage::UserNotObserving {
-                                    user,
-                                });
-                            }
-                        }
-                        VisibilityState::Visible => {
-                            if !viewing_users.contains(&user) {
-                                viewing_users.push(user.clone());
-                                send_msg(ServerMessage::UserObserving { user });
-                            }
-                        }
+                ServerStateMessage::UserDisconnected { conn } => {
+                    let Some(user) = sessions.user(conn).map(str::to_string)
+                    else {
+                        continue;
+                    };
+                    let before = sessions.presence(&user);
+                    sessions.leave(conn);
+                    send_presence_diff(&sessions, &user, before);
+                }
+                ServerStateMessage::UserReadMessages { user, up_to } => {
+                    sessions.touch(&user);
+                    let latest_id = current_message_id.checked_sub(1);
+                    if history.mark_read(&user, up_to, latest_id) {
+                        send_msg(ServerMessage::MessagesRead {
+                            by_user: user,
+                            up_to,
+                        });
                     }
                 }
+                ServerStateMessage::VisbilityUpdate { conn, vis } => {
+                    let Some(user) = sessions.user(conn).map(str::to_string)
+                    else {
+                        continue;
+                    };
+                    let before = sessions.presence(&user);
+                    sessions.set_visible(
+                        conn,
+                        matches!(vis, VisibilityState::Visible),
+                    );
+                    send_presence_diff(&sessions, &user, before);
+                }
+                ServerStateMessage::PresenceTick => {
+                    let expired =
+                        sessions.expire_custom_statuses(chrono::Utc::now());
+                    if !expired.is_empty() {
+                        send_msg(ServerMessage::CustomStatusUpdate {
+                            statuses: expired,
+                        });
+                    }
+                    // A save that's still running gets the newer times on a
+                    // later tick
+                    if let Some(ref save_tx) = last_seen_save_tx
+                        && let Some(unsaved) = sessions.unsaved_last_active()
+                    {
+                        let _ = save_tx.try_send(unsaved);
+                    }
+                }
+                ServerStateMessage::LastSeenSaved { version } => {
+                    sessions.last_active_saved(version);
+                }
+            }
+
+            let status_changes = sessions.status_changes(chrono::Utc::now());
+            if !status_changes.is_empty() {
+                send_msg(ServerMessage::UserStatusUpdate {
+                    statuses: status_changes,
+                });
             }
         }
     });
@@ -278,6 +618,55 @@ async fn main() {
         .unwrap();
 }
 
+/// Renders a message's markdown to HTML that is safe to insert into the page
+#[cfg(feature = "ssr")]
+fn render_markdown(message_md: &str) -> String {
+    let message_html_safe = message_md
+        .lines()
+        .map(|line| line.trim_end()) // Trim trailing spaces
+        .collect::<Vec<_>>() // Collect into a Vec
+        .join("  \n"); // Join with Markdown's line break syntax (two spaces + newline)
+
+    // Configure commonmark extensions
+    let mut comrak_options = comrak::Options::default();
+    macro_rules! enable_exts {
+        ($($x:ident),+ $(,)?) => {{
+            $(
+                comrak_options.extension.$x = true;
+            )*
+        }};
+    }
+    enable_exts! {
+        table,
+        strikethrough,
+        autolink,
+
+        // TODO: Implement CSS for this
+        alerts,
+
+        // TODO: Implement mathjax to SVG, then add math things
+
+        wikilinks_title_after_pipe,
+        underline,
+        subscript,
+        multiline_block_quotes,
+    };
+    comrak_options.render.hardbreaks = true;
+    comrak_options.render.escape = true;
+    comrak_options.render.ignore_empty_links = true;
+    comrak_options.parse.smart = true;
+
+    comrak::markdown_to_html(&message_html_safe, &comrak_options)
+}
+
+/// A client that goes silent is disconnected, and removed from presence,
+/// within HEARTBEAT_MAX_INTERVAL + LIVENESS_CHECK_INTERVAL. The write task
+/// pings regularly so even idle browsers keep producing pongs.
+#[cfg(feature = "ssr")]
+const HEARTBEAT_MAX_INTERVAL: Duration = Duration::from_secs(5);
+#[cfg(feature = "ssr")]
+const LIVENESS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 #[cfg(feature = "ssr")]
 async fn handler(
     ws: WebSocketUpgrade,
@@ -290,9 +679,28 @@ async fn handler(
 async fn handle_socket(ws: WebSocket, state: AppStateExt) {
     use futures::StreamExt;
 
+    // Messages addressed only to this connection, such as the handshake reply
+    const DIRECT_CHANNEL_CAPACITY: usize = 32;
+
+    let conn = state
+        .next_connection_id
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
     let (sender, receiver) = ws.split();
-    let read_task = tokio::spawn(handle_socket_read(receiver, state.clone()));
-    let write_task = tokio::spawn(handle_socket_write(sender, state.clone()));
+    let (direct_tx, direct_rx) =
+        tokio::sync::mpsc::channel(DIRECT_CHANNEL_CAPACITY);
+    let read_task = tokio::spawn(handle_socket_read(
+        receiver,
+        state.state_tx.clone(),
+        conn,
+        direct_tx,
+    ));
+    let write_task = tokio::spawn(handle_socket_write(
+        sender,
+        state.state_broadcast_tx.subscribe(),
+        state.state_tx.clone(),
+        conn,
+        direct_rx,
+    ));
 
     let res = futures::join!(read_task, write_task);
     if let Err(e) = res.0 {
@@ -305,121 +713,296 @@ async fn handle_socket(ws: WebSocket, state: AppStateExt) {
 
 #[cfg(feature = "ssr")]
 async fn handle_socket_read(
-    mut ws: SplitStream<WebSocket>,
-    state: AppStateExt,
+    mut ws: impl Stream<Item = Result<axum::extract::ws::Message, axum::Error>>
+        + Unpin,
+    state_tx: tokio::sync::mpsc::Sender<ServerStateMessage>,
+    conn: ConnectionId,
+    direct_tx: tokio::sync::mpsc::Sender<ServerMessage>,
 ) {
+    use axum::extract::ws::Message;
     use codee::{binary::MsgpackSerdeCodec, HybridDecoder};
     use futures::StreamExt;
     use rss_chat::socket::*;
-    use std::time::{Duration, Instant};
+    use tokio::time::Instant;
 
-    const HEARTBEAT_MAX_INTERVAL: Duration = Duration::from_secs(5);
+    const INIT_TIMEOUT: Duration = Duration::from_secs(5);
 
-    let mut latest_heartbeat = Instant::now();
+    let send_error = |code: ProtocolErrorCode, message: &str| {
+        let direct_tx = direct_tx.clone();
+        let message = message.to_string();
+        async move {
+            let _ =
+                direct_tx.send(ServerMessage::Error { code, message }).await;
+        }
+    };
+
+    let init_deadline = tokio::time::sleep(INIT_TIMEOUT);
+    tokio::pin!(init_deadline);
 
-    let name = loop {
-        let Some(Ok(msg)) = ws.next().await else {
+    let (name, protocol_version) = loop {
+        let msg = tokio::select! {
+            msg = ws.next() => msg,
+            _ = &mut init_deadline => {
+                log::info!("Client did not send an init message in time");
+                return;
+            }
+        };
+        let Some(Ok(msg)) = msg else {
             return;
         };
+        match msg {
+            Message::Close(_) => return,
+            Message::Ping(_) | Message::Pong(_) => continue,
+            _ => (),
+        }
         if msg.to_text().is_ok_and(|v| v == "<Heartbeat>") {
-            latest_heartbeat = Instant::now();
             continue;
         }
         let decoded: Result<ClientMessage, _> =
             MsgpackSerdeCodec::decode_bin(&msg.into_data());
         match decoded {
-            Ok(ClientMessage::InitMessage { name }) => {
-                break name;
+            Ok(ClientMessage::InitMessage {
+                name,
+                protocol_version,
+            }) => {
+                break (name, protocol_version);
             }
-            _ => {
+            Ok(_) => {
                 log::error!("First message from client was not init message");
+                send_error(
+                    ProtocolErrorCode::MissingInit,
+                    "The first message must be an init message",
+                )
+                .await;
+                return;
+            }
+            Err(e) => {
+                log::error!("Failed decoding init message:\n{e}");
+                send_error(
+                    ProtocolErrorCode::MalformedMessage,
+                    "The init message could not be decoded",
+                )
+                .await;
                 return;
             }
         }
     };
 
-    if state
-        .state_tx
-        .send(ServerStateMessage::UserJoined { name: name.clone() })
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+        log::info!(
+            "Client `{name}` uses unsupported protocol version \
+            {protocol_version}"
+        );
+        let _ = direct_tx
+            .send(ServerMessage::ReloadRequired {
+                server_version: PROTOCOL_VERSION,
+            })
+            .await;
+        return;
+    }
+
+    if !validate_name(&name) {
+        log::info!("Client tried to join as `{name}`");
+        send_error(
+            ProtocolErrorCode::InvalidName,
+            &format!("The name \"{name}\" can't be used"),
+        )
+        .await;
+        return;
+    }
+
+    if direct_tx
+        .send(ServerMessage::Welcome {
+            server_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|i| i.to_string()).collect(),
+        })
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    if state_tx
+        .send(ServerStateMessage::UserJoined {
+            conn,
+            name: name.clone(),
+            direct_tx: direct_tx.clone(),
+        })
         .await
         .is_err()
     {
         log::error!("Channel not open or full when user joined");
+        send_error(
+            ProtocolErrorCode::ServerUnavailable,
+            "The server is not accepting connections right now",
+        )
+        .await;
         return;
     }
 
-    while let Some(msg) = ws.next().await {
-        let Ok(msg) = msg else {
+    let mut latest_heartbeat = Instant::now();
+    let mut liveness_check = tokio::time::interval(LIVENESS_CHECK_INTERVAL);
+
+    loop {
+        let msg = tokio::select! {
+            msg = ws.next() => msg,
+            _ = liveness_check.tick() => {
+                if latest_heartbeat.elapsed() > HEARTBEAT_MAX_INTERVAL {
+                    log::info!("Client `{name}` timed out");
+                    break;
+                }
+                continue;
+            }
+        };
+        let Some(Ok(msg)) = msg else {
             break;
         };
+        // Any frame at all, including pongs to our pings, shows that the
+        // client is still there
+        latest_heartbeat = Instant::now();
+        match msg {
+            Message::Close(_) => break,
+            Message::Ping(_) | Message::Pong(_) => continue,
+            _ => (),
+        }
         if msg.to_text().is_ok_and(|v| v == "<Heartbeat>") {
-            latest_heartbeat = Instant::now();
             continue;
         }
-        if latest_heartbeat.elapsed() > HEARTBEAT_MAX_INTERVAL {
-            log::info!("Client `{name}` timed out");
-            break;
-        }
         let data = msg.into_data();
         let decoded_result: Result<ClientMessage, _> =
             MsgpackSerdeCodec::decode_bin(&data);
-        let Ok(decoded_result) = decoded_result else {
-            break;
+        let decoded_result = match decoded_result {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("Failed decoding message from `{name}`:\n{e}");
+                send_error(
+                    ProtocolErrorCode::MalformedMessage,
+                    "A message could not be decoded",
+                )
+                .await;
+                break;
+            }
         };
-        match decoded_result {
-            ClientMessage::InitMessage { name } => {
+        let state_msg = match decoded_result {
+            ClientMessage::InitMessage { name, .. } => {
                 log::error!("Client {name} sent two init messages");
+                send_error(
+                    ProtocolErrorCode::DuplicateInit,
+                    "The connection was already initialized",
+                )
+                .await;
                 break;
             }
-            ClientMessage::Typed => {
-                let _ = state
-                    .state_tx
-                    .send(ServerStateMessage::UserTyped { name: name.clone() })
-                    .await;
-            }
-            ClientMessage::SendMessage { message } => {
-                let _ = state
-                    .state_tx
-                    .send(ServerStateMessage::NewMessage { message })
-                    .await;
+            ClientMessage::Typed => ServerStateMessage::UserTyped { conn },
+            ClientMessage::SendMessage { mut message } => {
+                // Whoever joined on this connection is the sender, whatever
+                // the client claims
+                message.sender = name.clone();
+                ServerStateMessage::NewMessage { message }
             }
-            ClientMessage::ReadMessages { earliest } => {
-                let _ = state
-                    .state_tx
-                    .send(ServerStateMessage::UserReadMessages {
-                        user: name.clone(),
-                        earliest,
-                    })
-                    .await;
+            ClientMessage::ReadMessages { up_to } => {
+                ServerStateMessage::UserReadMessages {
+                    user: name.clone(),
+                    up_to,
+                }
             }
             ClientMessage::VisibilityUpdate(vis) => {
-                let _ = state
-                    .state_tx
-                    .send(ServerStateMessage::VisbilityUpdate {
-                        user: name.clone(),
-                        vis,
-                    })
-                    .await;
+                ServerStateMessage::VisbilityUpdate { conn, vis }
             }
+            ClientMessage::SetCustomStatus(status) => {
+                ServerStateMessage::CustomStatusSet { conn, status }
+            }
+        };
+        if state_tx.send(state_msg).await.is_err() {
+            log::error!("State channel closed while `{name}` was connected");
+            send_error(
+                ProtocolErrorCode::ServerUnavailable,
+                "The server is shutting down",
+            )
+            .await;
+            break;
         }
     }
-    let _ = state
-        .state_tx
-        .send(ServerStateMessage::UserDisconnected { name })
+    let _ = state_tx
+        .send(ServerStateMessage::UserDisconnected { conn })
         .await;
 }
 
+/// Close frame to send after `msg`, if it ends the connection
+#[cfg(feature = "ssr")]
+fn closing_frame(
+    msg: &ServerMessage,
+) -> Option<axum::extract::ws::CloseFrame<'static>> {
+    use axum::extract::ws::{close_code, CloseFrame};
+    use rss_chat::socket::ProtocolErrorCode;
+
+    let (code, reason) = match msg {
+        ServerMessage::Error { code, message } => {
+            let close_code = match code {
+                ProtocolErrorCode::MalformedMessage => close_code::INVALID,
+                ProtocolErrorCode::MissingInit
+                | ProtocolErrorCode::DuplicateInit
+                | ProtocolErrorCode::InvalidName => close_code::POLICY,
+                ProtocolErrorCode::ServerUnavailable => close_code::AGAIN,
+            };
+            (close_code, message.clone())
+        }
+        ServerMessage::ReloadRequired { .. } => {
+            (close_code::POLICY, "Reload required".to_string())
+        }
+        _ => return None,
+    };
+    Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    })
+}
+
 #[cfg(feature = "ssr")]
 async fn handle_socket_write(
-    mut ws: SplitSink<WebSocket, axum::extract::ws::Message>,
-    state: AppStateExt,
+    mut ws: impl Sink<axum::extract::ws::Message> + Unpin,
+    mut rx: tokio::sync::broadcast::Receiver<ServerMessage>,
+    state_tx: tokio::sync::mpsc::Sender<ServerStateMessage>,
+    conn: ConnectionId,
+    mut direct_rx: tokio::sync::mpsc::Receiver<ServerMessage>,
 ) {
     use axum::extract::ws::Message;
     use codee::{binary::MsgpackSerdeCodec, HybridEncoder};
     use futures::SinkExt;
+    use std::time::Duration;
+    use tokio::sync::broadcast::error::RecvError;
 
-    let mut rx = state.state_broadcast_tx.subscribe();
-    while let Ok(msg) = rx.recv().await {
+    const PING_INTERVAL: Duration = Duration::from_secs(2);
+
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    loop {
+        let msg = tokio::select! {
+            biased;
+            msg = direct_rx.recv() => match msg {
+                Some(msg) => msg,
+                // The read half is done, so nobody is listening anymore
+                None => break,
+            },
+            msg = rx.recv() => match msg {
+                Ok(msg) => msg,
+                // Missed broadcasts are made up for by sending the whole
+                // state again
+                Err(RecvError::Lagged(missed)) => {
+                    log::warn!("Connection {conn} missed {missed} broadcasts");
+                    let _ = state_tx
+                        .send(ServerStateMessage::Resync { conn })
+                        .await;
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = ping.tick() => {
+                if ws.send(Message::Ping(Vec::new())).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
         let encoded = match MsgpackSerdeCodec::encode_bin(&msg) {
             Ok(v) => v,
             Err(e) => {
@@ -430,7 +1013,12 @@ async fn handle_socket_write(
         if ws.send(Message::Binary(encoded)).await.is_err() {
             return;
         }
+        if let Some(frame) = closing_frame(&msg) {
+            let _ = ws.send(Message::Close(Some(frame))).await;
+            return;
+        }
     }
+    let _ = ws.close().await;
 }
 
 #[cfg(not(feature = "ssr"))]
@@ -439,3 +1027,133 @@ pub fn main() {
     // unless we want this to work with e.g., Trunk for pure client-side testing
     // see lib.rs for hydration function instead
 }
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+    use axum::extract::ws::Message;
+    use codee::{binary::MsgpackSerdeCodec, HybridDecoder, HybridEncoder};
+    use futures::StreamExt;
+    use rss_chat::socket::{
+        ClientMessage, ProtocolErrorCode, UserMessage, PROTOCOL_VERSION,
+    };
+
+    fn frame(msg: &ClientMessage) -> Result<Message, axum::Error> {
+        Ok(Message::Binary(MsgpackSerdeCodec::encode_bin(msg).unwrap()))
+    }
+
+    fn init(name: &str) -> ClientMessage {
+        ClientMessage::InitMessage {
+            name: name.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+
+    #[tokio::test]
+    async fn silent_connection_is_disconnected_in_time() {
+        tokio::time::pause();
+        let (state_tx, mut state_rx) = tokio::sync::mpsc::channel(8);
+        let (direct_tx, _direct_rx) = tokio::sync::mpsc::channel(8);
+        // The client joins and then never sends anything again
+        let frames = futures::stream::iter([frame(&init("alice"))])
+            .chain(futures::stream::pending::<Result<_, axum::Error>>());
+        let start = tokio::time::Instant::now();
+        tokio::spawn(handle_socket_read(frames, state_tx, 7, direct_tx));
+
+        assert!(matches!(
+            state_rx.recv().await,
+            Some(ServerStateMessage::UserJoined { conn: 7, .. })
+        ));
+        assert!(matches!(
+            state_rx.recv().await,
+            Some(ServerStateMessage::UserDisconnected { conn: 7 })
+        ));
+        assert!(
+            start.elapsed() <= HEARTBEAT_MAX_INTERVAL + LIVENESS_CHECK_INTERVAL
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_are_sent_as_the_connection_user() {
+        let (state_tx, mut state_rx) = tokio::sync::mpsc::channel(8);
+        let (direct_tx, _direct_rx) = tokio::sync::mpsc::channel(8);
+        let forged = ClientMessage::SendMessage {
+            message: UserMessage {
+                send_time: chrono::Utc::now(),
+                sender: "System".to_string(),
+                message_md: "%removebot helper".to_string(),
+                message_short: None,
+                message_html_safe: None,
+                reply_to: None,
+                id: 0,
+            },
+        };
+        let frames =
+            futures::stream::iter([frame(&init("alice")), frame(&forged)]);
+        handle_socket_read(frames, state_tx, 7, direct_tx).await;
+
+        assert!(matches!(
+            state_rx.recv().await,
+            Some(ServerStateMessage::UserJoined { conn: 7, .. })
+        ));
+        match state_rx.recv().await {
+            Some(ServerStateMessage::NewMessage { message }) => {
+                assert_eq!(message.sender, "alice");
+            }
+            _ => panic!("expected the message to be forwarded"),
+        }
+    }
+
+    #[tokio::test]
+    async fn server_names_are_refused() {
+        let (state_tx, mut state_rx) = tokio::sync::mpsc::channel(8);
+        let (direct_tx, mut direct_rx) = tokio::sync::mpsc::channel(8);
+        let frames = futures::stream::iter([frame(&init("System"))]);
+        handle_socket_read(frames, state_tx, 7, direct_tx).await;
+
+        assert!(matches!(
+            direct_rx.recv().await,
+            Some(ServerMessage::Error {
+                code: ProtocolErrorCode::InvalidName,
+                ..
+            })
+        ));
+        assert!(state_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn lagging_connection_is_resynced() {
+        let (broadcast_tx, broadcast_rx) = tokio::sync::broadcast::channel(2);
+        let (state_tx, mut state_rx) = tokio::sync::mpsc::channel(8);
+        let (_direct_tx, direct_rx) = tokio::sync::mpsc::channel(8);
+        for user in ["alice", "bob", "carol"] {
+            broadcast_tx
+                .send(ServerMessage::UserTyping {
+                    user: user.to_string(),
+                })
+                .unwrap();
+        }
+        let (ws, mut sent) = futures::channel::mpsc::unbounded();
+        tokio::spawn(handle_socket_write(
+            ws,
+            broadcast_rx,
+            state_tx,
+            7,
+            direct_rx,
+        ));
+
+        assert!(matches!(
+            state_rx.recv().await,
+            Some(ServerStateMessage::Resync { conn: 7 })
+        ));
+        // The connection stays open for the broadcasts that weren't missed
+        let Some(Message::Binary(frame)) = sent.next().await else {
+            panic!("expected a message to be sent");
+        };
+        let msg: ServerMessage = MsgpackSerdeCodec::decode_bin(&frame).unwrap();
+        assert!(matches!(
+            msg,
+            ServerMessage::UserTyping { ref user } if user == "bob"
+        ));
+    }
+}
@@ -0,0 +1,353 @@
+use std::{
+    collections::VecDeque, convert::Infallible, net::SocketAddr, sync::Mutex,
+};
+
+use async_openai::{
+    error::OpenAIError,
+    types::{
+        ChatCompletionRequestMessage, ChatCompletionResponseStream,
+        CreateChatCompletionRequest, CreateChatCompletionResponse,
+    },
+};
+use axum::{
+    response::{
+        sse::{Event, Sse},
+        IntoResponse, Response,
+    },
+    routing::post,
+    Json, Router,
+};
+use futures::{future::BoxFuture, stream};
+use serde_json::json;
+use tokio::net::TcpListener;
+
+use crate::{ai, backend::ChatBackend};
+
+/// Something a [`ScriptedBackend`] answers with
+pub enum ScriptedReply {
+    Text(String),
+    /// Asks for tools to be called, given by name and JSON arguments. Only
+    /// streamed requests can be answered like this.
+    #[cfg(test)]
+    ToolCalls(Vec<(&'static str, String)>),
+}
+
+/// Answers requests with a script of replies, and by echoing the question
+/// back once the script runs out. Streamed replies arrive a word at a time.
+pub struct ScriptedBackend {
+    replies: Mutex<VecDeque<ScriptedReply>>,
+    /// Every request answered so far, oldest first
+    #[cfg(test)]
+    requests: Mutex<Vec<CreateChatCompletionRequest>>,
+}
+
+impl ScriptedBackend {
+    pub fn new(replies: Vec<String>) -> ScriptedBackend {
+        ScriptedBackend::with_replies(
+            replies.into_iter().map(ScriptedReply::Text).collect(),
+        )
+    }
+    pub fn with_replies(replies: Vec<ScriptedReply>) -> ScriptedBackend {
+        ScriptedBackend {
+            replies: Mutex::new(replies.into()),
+            #[cfg(test)]
+            requests: Mutex::default(),
+        }
+    }
+    #[cfg(test)]
+    pub fn requests(&self) -> Vec<CreateChatCompletionRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+    fn next_reply(
+        &self,
+        request: &CreateChatCompletionRequest,
+    ) -> ScriptedReply {
+        #[cfg(test)]
+        self.requests.lock().unwrap().push(request.clone());
+        self.replies
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| ScriptedReply::Text(echo_reply(request)))
+    }
+}
+
+impl ChatBackend for ScriptedBackend {
+    fn create_stream(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> BoxFuture<'_, Result<ChatCompletionResponseStream, OpenAIError>> {
+        let reply = self.next_reply(&request);
+        Box::pin(async move {
+            let chunks = match reply {
+                ScriptedReply::Text(reply) => stream_chunks(&request, &reply),
+                #[cfg(test)]
+                ScriptedReply::ToolCalls(calls) => {
+                    tool_call_chunks(&request, &calls)
+                }
+            };
+            let chunks = chunks.into_iter().map(|chunk| {
+                serde_json::from_value(chunk)
+                    .map_err(OpenAIError::JSONDeserialize)
+            });
+            let stream: ChatCompletionResponseStream =
+                Box::pin(stream::iter(chunks));
+            Ok(stream)
+        })
+    }
+    fn create(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> BoxFuture<'_, Result<CreateChatCompletionResponse, OpenAIError>> {
+        let reply = self.next_reply(&request);
+        Box::pin(async move {
+            match reply {
+                ScriptedReply::Text(reply) => {
+                    serde_json::from_value(completion(&request, &reply))
+                        .map_err(OpenAIError::JSONDeserialize)
+                }
+                #[cfg(test)]
+                ScriptedReply::ToolCalls(_) => {
+                    Err(OpenAIError::InvalidArgument(
+                        "scripted tool calls can only be streamed".to_string(),
+                    ))
+                }
+            }
+        })
+    }
+}
+
+/// Serves a minimal OpenAI-compatible chat completions API that echoes
+/// questions back, for trying out bots without a real provider
+pub async fn serve(addr: SocketAddr) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Failed binding mock LLM server to {addr}:\n{e}");
+            return;
+        }
+    };
+    log::info!("Mock LLM server listening on http://{addr}/v1");
+    serve_on(listener).await;
+}
+
+/// Like [`serve`], but on a listener that's already bound
+async fn serve_on(listener: TcpListener) {
+    let app =
+        Router::new().route("/v1/chat/completions", post(chat_completions));
+    if let Err(e) = axum::serve(listener, app).await {
+        log::error!("Mock LLM server failed:\n{e}");
+    }
+}
+
+async fn chat_completions(
+    Json(request): Json<CreateChatCompletionRequest>,
+) -> Response {
+    let reply = echo_reply(&request);
+    if request.stream != Some(true) {
+        return Json(completion(&request, &reply)).into_response();
+    }
+    let events = stream_chunks(&request, &reply)
+        .into_iter()
+        .map(|chunk| Event::default().data(chunk.to_string()))
+        .chain(std::iter::once(Event::default().data("[DONE]")))
+        .map(Ok::<_, Infallible>);
+    Sse::new(stream::iter(events)).into_response()
+}
+
+/// Repeats the text of the latest user message
+fn echo_reply(request: &CreateChatCompletionRequest) -> String {
+    let question = request
+        .messages
+        .iter()
+        .rev()
+        .find(|message| {
+            matches!(message, ChatCompletionRequestMessage::User(_))
+        })
+        .and_then(ai::message_text)
+        .unwrap_or_default();
+    // Bots wrap questions in a tag saying who is asking, which isn't worth
+    // repeating
+    let question = question
+        .strip_prefix("<message")
+        .and_then(|rest| rest.split_once(">\n"))
+        .and_then(|(_, rest)| rest.strip_suffix("\n</message>"))
+        .unwrap_or(question);
+    format!("You said: {question}")
+}
+
+/// Rough token count, since the mock has no tokenizer
+fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Rough usage of a request, counting the text of its messages and the reply
+fn usage(
+    request: &CreateChatCompletionRequest,
+    reply: &str,
+) -> serde_json::Value {
+    let prompt_tokens: usize = request
+        .messages
+        .iter()
+        .filter_map(ai::message_text)
+        .map(estimate_tokens)
+        .sum();
+    let completion_tokens = estimate_tokens(reply);
+    json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": prompt_tokens + completion_tokens,
+    })
+}
+
+/// The chunks of a streamed completion, as an API would send them
+fn stream_chunks(
+    request: &CreateChatCompletionRequest,
+    reply: &str,
+) -> Vec<serde_json::Value> {
+    let created = chrono::Utc::now().timestamp();
+    let chunk = |delta: serde_json::Value, finish_reason: Option<&str>| {
+        json!({
+            "id": "mock",
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": request.model,
+            "choices": [{
+                "index": 0,
+                "delta": delta,
+                "finish_reason": finish_reason,
+            }],
+        })
+    };
+    let mut chunks = vec![chunk(json!({ "role": "assistant" }), None)];
+    chunks.extend(
+        reply
+            .split_inclusive(' ')
+            .map(|word| chunk(json!({ "content": word }), None)),
+    );
+    chunks.push(chunk(json!({}), Some("stop")));
+    if request
+        .stream_options
+        .as_ref()
+        .is_some_and(|options| options.include_usage)
+    {
+        chunks.push(json!({
+            "id": "mock",
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": request.model,
+            "choices": [],
+            "usage": usage(request, reply),
+        }));
+    }
+    chunks
+}
+
+/// The single chunk of a streamed completion that asks for tools to be
+/// called
+#[cfg(test)]
+fn tool_call_chunks(
+    request: &CreateChatCompletionRequest,
+    calls: &[(&str, String)],
+) -> Vec<serde_json::Value> {
+    let tool_calls: Vec<serde_json::Value> = calls
+        .iter()
+        .enumerate()
+        .map(|(index, (name, arguments))| {
+            json!({
+                "index": index,
+                "id": format!("call_{index}"),
+                "type": "function",
+                "function": {
+                    "name": name,
+                    "arguments": arguments,
+                },
+            })
+        })
+        .collect();
+    vec![json!({
+        "id": "mock",
+        "object": "chat.completion.chunk",
+        "created": chrono::Utc::now().timestamp(),
+        "model": request.model,
+        "choices": [{
+            "index": 0,
+            "delta": {
+                "role": "assistant",
+                "tool_calls": tool_calls,
+            },
+            "finish_reason": "tool_calls",
+        }],
+    })]
+}
+
+/// A whole completion, as an API would send it
+fn completion(
+    request: &CreateChatCompletionRequest,
+    reply: &str,
+) -> serde_json::Value {
+    json!({
+        "id": "mock",
+        "object": "chat.completion",
+        "created": chrono::Utc::now().timestamp(),
+        "model": request.model,
+        "choices": [{
+            "index": 0,
+            "message": {
+                "role": "assistant",
+                "content": reply,
+            },
+            "finish_reason": "stop",
+        }],
+        "usage": usage(request, reply),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ai::{AiContext, HistoryBudget, Query},
+        backend::OpenAiBackend,
+        tools::ToolRegistry,
+        usage::UsageLimits,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn bots_reply_through_the_mock_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_on(listener));
+
+        let (state_tx, _state_rx) = tokio::sync::mpsc::channel(8);
+        let ai_context = AiContext::new(
+            None,
+            HistoryBudget {
+                max_chars: 10_000,
+                summarize: false,
+            },
+            None,
+            None,
+            vec![],
+            ToolRegistry::new(state_tx),
+            UsageLimits::default(),
+        )
+        .with_backend(OpenAiBackend::new(format!("http://{addr}/v1"), None));
+        let (deltas_tx, mut deltas_rx) = tokio::sync::mpsc::unbounded_channel();
+        let query = Query {
+            text: "hello there",
+            user: "alice",
+            context: vec![],
+        };
+        let reply = ai_context.get_response(query, None, deltas_tx).await;
+
+        assert_eq!(reply.unwrap(), "You said: hello there");
+        let mut streamed = String::new();
+        while let Some(delta) = deltas_rx.recv().await {
+            streamed.push_str(&delta);
+        }
+        assert_eq!(streamed, "You said: hello there");
+        assert!(ai_context.usage("alice").user.total.total() > 0);
+    }
+}
@@ -0,0 +1,79 @@
+use std::path::Path;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Reads a JSON file, returning `None` if it doesn't exist. A file that can't
+/// be parsed is moved aside first, so that saving over it later doesn't lose
+/// what was in it.
+pub async fn load_json<T: DeserializeOwned>(
+    path: &Path,
+) -> std::io::Result<Option<T>> {
+    let data = match tokio::fs::read(path).await {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    match serde_json::from_slice(&data) {
+        Ok(v) => Ok(Some(v)),
+        Err(e) => {
+            let mut invalid_path = path.as_os_str().to_owned();
+            invalid_path.push(format!(
+                ".invalid-{}",
+                chrono::Utc::now().format("%Y%m%d%H%M%S")
+            ));
+            tokio::fs::rename(path, &invalid_path).await?;
+            log::error!(
+                "Failed parsing {}, moved it to {}:\n{e}",
+                path.display(),
+                Path::new(&invalid_path).display()
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Writes a value as JSON to a temporary file next to `path` and then renames
+/// it into place, so a crash never leaves a half-written file behind
+pub async fn save_json<T: Serialize>(
+    path: &Path,
+    value: &T,
+) -> std::io::Result<()> {
+    let data = serde_json::to_vec_pretty(value)?;
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    tokio::fs::write(&tmp_path, data).await?;
+    tokio::fs::rename(&tmp_path, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn invalid_file_is_moved_aside() {
+        let dir = std::env::temp_dir()
+            .join(format!("rss-chat-persist-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("bots.json");
+        tokio::fs::write(&path, "[{\"name\": ").await.unwrap();
+
+        let loaded: Option<Vec<String>> = load_json(&path).await.unwrap();
+        assert!(loaded.is_none());
+        assert!(!tokio::fs::try_exists(&path).await.unwrap());
+        let mut entries = tokio::fs::read_dir(&dir).await.unwrap();
+        let moved = entries.next_entry().await.unwrap().unwrap();
+        assert_eq!(
+            tokio::fs::read_to_string(moved.path()).await.unwrap(),
+            "[{\"name\": "
+        );
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_file_loads_as_nothing() {
+        let path = std::env::temp_dir().join("rss-chat-persist-missing.json");
+        let loaded: Option<Vec<String>> = load_json(&path).await.unwrap();
+        assert!(loaded.is_none());
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use rss_chat::socket::{
+    CustomStatus, ServerMessage, UserCustomStatus, UserStatus, UserStatusInfo,
+};
+use tokio::sync::mpsc::Sender;
+
+/// Seconds without activity after which an online user is shown as idle
+const IDLE_AFTER_SECS: i64 = 5 * 60;
+/// Seconds without activity after which an online user is shown as away
+const AWAY_AFTER_SECS: i64 = 30 * 60;
+
+/// Identifies a single websocket connection. One user may have several at once
+/// (multiple tabs or devices).
+pub type ConnectionId = u64;
+
+/// When each user was last active, by name
+pub type LastActive = HashMap<String, DateTime<Utc>>;
+
+struct Session {
+    user: String,
+    /// Messages meant for this connection only
+    direct_tx: Sender<ServerMessage>,
+    /// Whether this tab is currently visible to the user
+    visible: bool,
+    /// Whether the user recently typed in this tab
+    typing: bool,
+    /// Bumped on every keystroke so that stale typing timeouts can be ignored
+    typing_generation: u64,
+}
+
+/// What the rest of the chat sees of a user, aggregated over all of their
+/// connections
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UserPresence {
+    pub online: bool,
+    pub typing: bool,
+    pub observing: bool,
+}
+
+/// Registry of open connections, keyed by connection rather than by name so
+/// that several tabs of the same user don't step on each other
+#[derive(Default)]
+pub struct SessionRegistry {
+    sessions: HashMap<ConnectionId, Session>,
+    /// Latest activity of every user that has ever connected, kept after they
+    /// disconnect so that "last seen" survives
+    last_active: LastActive,
+    /// Bumped whenever `last_active` changes
+    last_active_version: u64,
+    /// Version of `last_active` that was last saved successfully
+    saved_version: u64,
+    /// Statuses as they were last broadcast, used to find transitions
+    reported_statuses: HashMap<String, UserStatus>,
+    /// Statuses set by users themselves, kept while they're offline
+    custom_statuses: HashMap<String, CustomStatus>,
+}
+
+impl SessionRegistry {
+    /// Creates a registry that remembers when users were last active
+    pub fn with_last_active(last_active: LastActive) -> Self {
+        let reported_statuses = last_active
+            .keys()
+            .map(|user| (user.clone(), UserStatus::Offline))
+            .collect();
+        SessionRegistry {
+            last_active,
+            reported_statuses,
+            ..Default::default()
+        }
+    }
+    pub fn join(
+        &mut self,
+        conn: ConnectionId,
+        user: String,
+        direct_tx: Sender<ServerMessage>,
+    ) {
+        self.sessions.insert(
+            conn,
+            Session {
+                user: user.clone(),
+                direct_tx,
+                visible: false,
+                typing: false,
+                typing_generation: 0,
+            },
+        );
+        self.touch(&user);
+    }
+    /// Removes a connection, returning the name of the user it belonged to
+    pub fn leave(&mut self, conn: ConnectionId) -> Option<String> {
+        let user = self.user(conn)?.to_string();
+        self.touch(&user);
+        self.sessions.remove(&conn);
+        Some(user)
+    }
+    /// Sends a message to a single connection without waiting for it. A
+    /// client that can't keep up misses the message.
+    pub fn send_to(&self, conn: ConnectionId, msg: ServerMessage) {
+        if let Some(session) = self.sessions.get(&conn)
+            && session.direct_tx.try_send(msg).is_err()
+        {
+            log::warn!("Dropped direct message to connection {conn}");
+        }
+    }
+    /// Records activity by a connected user. Senders without a connection,
+    /// like bots, are ignored.
+    pub fn touch(&mut self, user: &str) {
+        if self.sessions.values().any(|session| session.user == user) {
+            self.last_active.insert(user.to_string(), Utc::now());
+            self.last_active_version += 1;
+        }
+    }
+    pub fn user(&self, conn: ConnectionId) -> Option<&str> {
+        self.sessions.get(&conn).map(|session| session.user.as_str())
+    }
+    pub fn set_visible(&mut self, conn: ConnectionId, visible: bool) {
+        let Some(session) = self.sessions.get_mut(&conn) else {
+            return;
+        };
+        session.visible = visible;
+        if visible {
+            let user = session.user.clone();
+            self.touch(&user);
+        }
+    }
+    /// Marks the connection as typing and returns the generation to pass to
+    /// [`Self::typing_expired`] once the typing timeout elapses
+    pub fn typed(&mut self, conn: ConnectionId) -> Option<u64> {
+        let session = self.sessions.get_mut(&conn)?;
+        session.typing = true;
+        session.typing_generation += 1;
+        let generation = session.typing_generation;
+        let user = session.user.clone();
+        self.touch(&user);
+        Some(generation)
+    }
+    /// Clears the typing flag unless the connection typed again since
+    /// `generation` was handed out
+    pub fn typing_expired(&mut self, conn: ConnectionId, generation: u64) {
+        if let Some(session) = self.sessions.get_mut(&conn)
+            && session.typing_generation == generation
+        {
+            session.typing = false;
+        }
+    }
+    pub fn presence(&self, user: &str) -> UserPresence {
+        self.sessions
+            .values()
+            .filter(|session| session.user == user)
+            .fold(UserPresence::default(), |acc, session| UserPresence {
+                online: true,
+                typing: acc.typing || session.typing,
+                observing: acc.observing || session.visible,
+            })
+    }
+    /// Names of all users with at least one open connection, sorted
+    pub fn online_users(&self) -> Vec<String> {
+        let mut users: Vec<String> = self
+            .sessions
+            .values()
+            .map(|session| session.user.clone())
+            .collect();
+        users.sort();
+        users.dedup();
+        users
+    }
+    pub fn status(&self, user: &str, now: DateTime<Utc>) -> UserStatus {
+        if !self.sessions.values().any(|session| session.user == user) {
+            return UserStatus::Offline;
+        }
+        let inactive_secs = self
+            .last_active
+            .get(user)
+            .map(|last| (now - *last).num_seconds())
+            .unwrap_or_default();
+        if inactive_secs >= AWAY_AFTER_SECS {
+            UserStatus::Away
+        } else if inactive_secs >= IDLE_AFTER_SECS {
+            UserStatus::Idle
+        } else {
+            UserStatus::Active
+        }
+    }
+    /// Current status of every user that has ever connected
+    pub fn statuses(&self, now: DateTime<Utc>) -> Vec<UserStatusInfo> {
+        self.last_active
+            .iter()
+            .map(|(user, last_seen)| UserStatusInfo {
+                user: user.clone(),
+                status: self.status(user, now),
+                last_seen: *last_seen,
+            })
+            .collect()
+    }
+    /// Statuses that changed since this was last called
+    pub fn status_changes(
+        &mut self,
+        now: DateTime<Utc>,
+    ) -> Vec<UserStatusInfo> {
+        let changes: Vec<UserStatusInfo> = self
+            .statuses(now)
+            .into_iter()
+            .filter(|info| {
+                self.reported_statuses.get(&info.user) != Some(&info.status)
+            })
+            .collect();
+        for info in &changes {
+            self.reported_statuses.insert(info.user.clone(), info.status);
+        }
+        changes
+    }
+    pub fn set_custom_status(
+        &mut self,
+        user: &str,
+        status: Option<CustomStatus>,
+    ) {
+        self.touch(user);
+        match status {
+            Some(status) => {
+                self.custom_statuses.insert(user.to_string(), status);
+            }
+            None => {
+                self.custom_statuses.remove(user);
+            }
+        }
+    }
+    pub fn custom_statuses(&self) -> Vec<UserCustomStatus> {
+        self.custom_statuses
+            .iter()
+            .map(|(user, status)| UserCustomStatus {
+                user: user.clone(),
+                status: Some(status.clone()),
+            })
+            .collect()
+    }
+    /// Removes custom statuses past their expiry, returning the cleared ones
+    pub fn expire_custom_statuses(
+        &mut self,
+        now: DateTime<Utc>,
+    ) -> Vec<UserCustomStatus> {
+        let expired: Vec<String> = self
+            .custom_statuses
+            .iter()
+            .filter(|(_, status)| status.is_expired(now))
+            .map(|(user, _)| user.clone())
+            .collect();
+        expired
+            .into_iter()
+            .map(|user| {
+                self.custom_statuses.remove(&user);
+                UserCustomStatus { user, status: None }
+            })
+            .collect()
+    }
+    /// Returns a copy of the last activity of all users along with its
+    /// version if it changed since it was last saved, for saving to disk
+    pub fn unsaved_last_active(&self) -> Option<(u64, LastActive)> {
+        (self.last_active_version != self.saved_version)
+            .then(|| (self.last_active_version, self.last_active.clone()))
+    }
+    /// Records that the given version of the last activity was saved. It's
+    /// only considered unsaved again once it changes.
+    pub fn last_active_saved(&mut self, version: u64) {
+        self.saved_version = self.saved_version.max(version);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    fn registry_with(users: &[&str]) -> SessionRegistry {
+        let mut registry = SessionRegistry::default();
+        for (conn, user) in users.iter().enumerate() {
+            let (direct_tx, _) = tokio::sync::mpsc::channel(1);
+            registry.join(conn as ConnectionId, user.to_string(), direct_tx);
+        }
+        registry
+    }
+
+    fn after_secs(secs: i64) -> DateTime<Utc> {
+        Utc::now() + TimeDelta::seconds(secs)
+    }
+
+    #[test]
+    fn inactive_users_become_idle_then_away() {
+        let registry = registry_with(&["alice"]);
+        assert_eq!(registry.status("alice", Utc::now()), UserStatus::Active);
+        assert_eq!(
+            registry.status("alice", after_secs(IDLE_AFTER_SECS + 1)),
+            UserStatus::Idle
+        );
+        assert_eq!(
+            registry.status("alice", after_secs(AWAY_AFTER_SECS + 1)),
+            UserStatus::Away
+        );
+    }
+
+    #[test]
+    fn users_are_offline_once_their_last_connection_closes() {
+        let mut registry = registry_with(&["alice", "alice"]);
+        registry.leave(0);
+        assert_eq!(registry.status("alice", Utc::now()), UserStatus::Active);
+        registry.leave(1);
+        assert_eq!(registry.status("alice", Utc::now()), UserStatus::Offline);
+        // Last seen is kept for offline users
+        assert_eq!(registry.statuses(Utc::now()).len(), 1);
+    }
+
+    #[test]
+    fn activity_makes_idle_users_active_again() {
+        let mut registry = registry_with(&["alice"]);
+        registry.last_active.insert(
+            "alice".to_string(),
+            Utc::now() - TimeDelta::seconds(AWAY_AFTER_SECS + 1),
+        );
+        assert_eq!(registry.status("alice", Utc::now()), UserStatus::Away);
+        registry.typed(0);
+        assert_eq!(registry.status("alice", Utc::now()), UserStatus::Active);
+    }
+
+    #[test]
+    fn only_status_transitions_are_reported() {
+        let mut registry = registry_with(&["alice"]);
+        let changes = registry.status_changes(Utc::now());
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].status, UserStatus::Active);
+        assert!(registry.status_changes(Utc::now()).is_empty());
+
+        let changes = registry.status_changes(after_secs(IDLE_AFTER_SECS + 1));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].status, UserStatus::Idle);
+
+        registry.leave(0);
+        let changes = registry.status_changes(Utc::now());
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].status, UserStatus::Offline);
+    }
+
+    #[test]
+    fn last_active_stays_unsaved_until_a_save_succeeds() {
+        let mut registry = registry_with(&["alice"]);
+        let (version, _) = registry.unsaved_last_active().unwrap();
+        // A failed save never reports back, so the times are still unsaved
+        assert!(registry.unsaved_last_active().is_some());
+
+        registry.last_active_saved(version);
+        assert!(registry.unsaved_last_active().is_none());
+    }
+
+    #[test]
+    fn changes_during_a_save_stay_unsaved() {
+        let mut registry = registry_with(&["alice", "bob"]);
+        let (version, _) = registry.unsaved_last_active().unwrap();
+        registry.typed(1);
+        registry.last_active_saved(version);
+        let (_, unsaved) = registry.unsaved_last_active().unwrap();
+        assert!(unsaved.contains_key("bob"));
+    }
+}
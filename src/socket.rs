@@ -2,6 +2,29 @@ use leptos::prelude::*;
 use leptos_use::core::ConnectionReadyState;
 use serde::{Deserialize, Serialize};
 
+/// Version of the websocket protocol spoken by this build. Bump this whenever a
+/// change to [`ClientMessage`] or [`ServerMessage`] would stop an already open
+/// tab from decoding messages.
+///
+/// Versions so far:
+/// 1. The handshake with [`ServerMessage::Welcome`]
+/// 2. [`ServerMessage::Error`] and user statuses. Some servers already sent
+///    errors as version 1, which version 1 clients can't decode.
+/// 3. Custom statuses
+/// 4. Read watermarks and [`ServerMessage::History`]
+/// 5. Streamed messages
+/// 6. [`ProtocolErrorCode::InvalidName`]. Version 5 clients only see their
+///    connection closed when they use an invalid name.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+/// Oldest client protocol version the server still talks to. Clients older
+/// than this are asked to reload.
+pub const MIN_PROTOCOL_VERSION: u32 = 5;
+
+/// Optional features advertised to clients in [`ServerMessage::Welcome`]
+pub const CAPABILITIES: &[&str] =
+    &["read-receipts", "visibility", "bots", "streaming"];
+
 // TODO: Split this into two types for before and after the server does its thing
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UserMessage {
@@ -31,22 +54,167 @@ impl From<web_sys::VisibilityState> for VisibilityState {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ClientMessage {
-    InitMessage { name: String },
+    InitMessage {
+        name: String,
+        /// Defaults to 0 for clients from before the protocol was versioned
+        #[serde(default)]
+        protocol_version: u32,
+    },
     SendMessage { message: UserMessage },
     Typed,
-    ReadMessages { earliest: u32 },
+    /// Moves the user's read watermark to the highest message id they've seen
+    ReadMessages { up_to: u32 },
     VisibilityUpdate(VisibilityState),
+    /// Sets the user's custom status, or clears it if `None`
+    SetCustomStatus(Option<CustomStatus>),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ServerMessage {
-    MessagesRead { by_user: String, earliest: u32 },
+    /// `by_user` has seen every message with an id up to and including `up_to`
+    MessagesRead { by_user: String, up_to: u32 },
     MessageSent { message: UserMessage },
+    /// More text of a message that is still being written, like a bot reply.
+    /// Such messages are first sent without any HTML.
+    MessageStreaming { id: u32, delta: String },
+    /// The finished version of a streamed message, replacing the streamed text
+    MessageFinalized { message: UserMessage },
     UserTyping { user: String },
     UserStoppedTyping { user: String },
     OnlineUsersUpdate { users: Vec<String> },
     UserObserving { user: String },
     UserNotObserving { user: String },
+    /// Reply to an accepted [`ClientMessage::InitMessage`]
+    Welcome {
+        server_version: u32,
+        capabilities: Vec<String>,
+    },
+    /// The client's protocol version is no longer supported and the page has
+    /// to be reloaded to get a compatible bundle
+    ReloadRequired { server_version: u32 },
+    /// Recent messages and everyone's read watermarks, sent right after joining
+    History {
+        messages: Vec<UserMessage>,
+        read_watermarks: Vec<ReadWatermark>,
+    },
+    /// Status changes of users, or a snapshot of all known users right after
+    /// joining
+    UserStatusUpdate { statuses: Vec<UserStatusInfo> },
+    /// Custom statuses that were set or cleared, or a snapshot of all of them
+    /// right after joining
+    CustomStatusUpdate { statuses: Vec<UserCustomStatus> },
+    /// The server hit a protocol error and is about to close the connection
+    Error {
+        code: ProtocolErrorCode,
+        message: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum UserStatus {
+    /// Online and recently active
+    Active,
+    /// Online but hasn't done anything for a few minutes
+    Idle,
+    /// Online but hasn't done anything for a long time
+    Away,
+    Offline,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UserStatusInfo {
+    pub user: String,
+    pub status: UserStatus,
+    /// When the user was last active, or disconnected if they're offline
+    pub last_seen: chrono::DateTime<chrono::Utc>,
+}
+
+/// A free-form status set by a user, like "🍜 lunch until 1pm"
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CustomStatus {
+    pub emoji: Option<String>,
+    pub text: String,
+    /// When the status should be cleared automatically
+    pub expires: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl CustomStatus {
+    pub const MAX_TEXT_LEN: usize = 100;
+    pub const MAX_EMOJI_LEN: usize = 16;
+
+    pub fn is_expired(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+    /// Whether the status is short enough and not already expired
+    pub fn is_valid(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.text.chars().count() <= Self::MAX_TEXT_LEN
+            && self
+                .emoji
+                .as_ref()
+                .is_none_or(|emoji| emoji.len() <= Self::MAX_EMOJI_LEN)
+            && !self.is_expired(now)
+    }
+}
+
+impl std::fmt::Display for CustomStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.emoji {
+            Some(ref emoji) => write!(f, "{emoji} {}", self.text),
+            None => write!(f, "{}", self.text),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UserCustomStatus {
+    pub user: String,
+    /// `None` if the status was cleared
+    pub status: Option<CustomStatus>,
+}
+
+/// Everything the users list shows about a user
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserInfo {
+    pub name: String,
+    pub typing: bool,
+    pub observing: bool,
+    pub status: UserStatus,
+    pub last_seen: Option<chrono::DateTime<chrono::Utc>>,
+    pub custom_status: Option<CustomStatus>,
+}
+
+/// Reasons for the server to give up on a connection
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolErrorCode {
+    /// A message from the client could not be decoded
+    MalformedMessage,
+    /// The client sent something other than an init message first
+    MissingInit,
+    /// The client sent a second init message on the same connection
+    DuplicateInit,
+    /// The server's internal state is unavailable, e.g. while shutting down
+    ServerUnavailable,
+    /// The client tried to join with a name that [`validate_name`] rejects
+    InvalidName,
+}
+
+/// Names only the server itself may use, compared ignoring case
+const RESERVED_NAMES: [&str; 2] = ["system", "admin"];
+
+/// Check whether a name is acceptable to use. The server's own names and
+/// names like those of bots are rejected so that nobody can speak for them.
+pub fn validate_name(name: &str) -> bool {
+    let name = name.trim().to_lowercase();
+    !(name.is_empty()
+        || name.ends_with("(bot)")
+        || RESERVED_NAMES.contains(&name.as_str()))
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReadWatermark {
+    pub user: String,
+    /// Highest message id the user has seen
+    pub up_to: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -84,6 +252,7 @@ pub struct ConnectionState<SendFn>
 where
     SendFn: Fn(&ClientMessage) + Clone + Send + Sync + 'static,
 {
+    name: String,
     ready: Signal<ConnectionReadyState>,
     pub message: Signal<Option<ServerMessage>>,
     send: SendFn,
@@ -91,6 +260,18 @@ where
     typing: RwSignal<Vec<String>>,
     online: RwSignal<Vec<String>>,
     observing: RwSignal<Vec<String>>,
+    statuses: RwSignal<Vec<UserStatusInfo>>,
+    custom_statuses: RwSignal<Vec<UserCustomStatus>>,
+    /// Highest message id this user has reported as read
+    own_watermark: RwSignal<Option<u32>>,
+    /// Whether the page is showing, as reported by [`Self::set_page_visible`]
+    page_visible: RwSignal<bool>,
+    /// Ids of messages that arrived while the page was hidden, which count as
+    /// unread as long as no watermark is known
+    arrived_while_hidden: RwSignal<Vec<u32>>,
+    capabilities: RwSignal<Vec<String>>,
+    reload_required: RwSignal<bool>,
+    error: RwSignal<Option<(ProtocolErrorCode, String)>>,
 }
 
 impl<SendFn> ConnectionState<SendFn>
@@ -106,25 +287,114 @@ where
     pub fn online(&self) -> ReadSignal<Vec<String>> {
         self.online.read_only()
     }
-    pub fn users(&self) -> Signal<Vec<(String, bool, bool)>> {
+    /// Online users followed by offline users, most recently seen first
+    pub fn users(&self) -> Signal<Vec<UserInfo>> {
         let typing = self.typing;
         let online = self.online;
         let observing = self.observing;
+        let statuses = self.statuses;
+        let custom_statuses = self.custom_statuses;
         Signal::derive(move || {
-            online
-                .get()
-                .into_iter()
-                .map(move |i| {
-                    let is_typing = typing.get().contains(&i);
-                    let is_observing = observing.get().contains(&i);
-                    (i, is_typing, is_observing)
+            let statuses = statuses.get();
+            let online = online.get();
+            let custom_statuses = custom_statuses.get();
+            let now = chrono::Utc::now();
+            let custom_status = |user: &str| {
+                custom_statuses
+                    .iter()
+                    .find(|i| i.user == user)
+                    .and_then(|i| i.status.clone())
+                    .filter(|status| !status.is_expired(now))
+            };
+            let mut users: Vec<UserInfo> = online
+                .iter()
+                .map(|i| {
+                    let status = statuses.iter().find(|s| s.user == *i);
+                    UserInfo {
+                        name: i.clone(),
+                        typing: typing.get().contains(i),
+                        observing: observing.get().contains(i),
+                        status: status
+                            .map(|s| s.status)
+                            .filter(|s| *s != UserStatus::Offline)
+                            .unwrap_or(UserStatus::Active),
+                        last_seen: status.map(|s| s.last_seen),
+                        custom_status: custom_status(i),
+                    }
                 })
-                .collect()
+                .collect();
+            let mut offline: Vec<&UserStatusInfo> = statuses
+                .iter()
+                .filter(|s| !online.contains(&s.user))
+                .collect();
+            offline.sort_by_key(|s| std::cmp::Reverse(s.last_seen));
+            users.extend(offline.into_iter().map(|s| UserInfo {
+                name: s.user.clone(),
+                typing: false,
+                observing: false,
+                status: UserStatus::Offline,
+                last_seen: Some(s.last_seen),
+                custom_status: custom_status(&s.user),
+            }));
+            users
         })
     }
     pub fn observing(&self) -> ReadSignal<Vec<String>> {
         self.observing.read_only()
     }
+    /// Highest message id this user has seen
+    pub fn read_watermark(&self) -> ReadSignal<Option<u32>> {
+        self.own_watermark.read_only()
+    }
+    /// Ids of messages from other users that are newer than `watermark`,
+    /// oldest first. Without a watermark, like for new users or after the
+    /// server restarted, messages are unread if they arrived while the page
+    /// was hidden.
+    pub fn unread_after(
+        &self,
+        watermark: Signal<Option<u32>>,
+    ) -> Signal<Vec<u32>> {
+        let messages = self.messages;
+        let arrived_while_hidden = self.arrived_while_hidden;
+        let name = self.name.clone();
+        Signal::derive(move || {
+            let watermark = watermark.get();
+            let arrived_while_hidden = arrived_while_hidden.get();
+            messages
+                .get()
+                .iter()
+                .map(|i| i.get())
+                .filter(|i| {
+                    i.message.sender != name
+                        && match watermark {
+                            Some(watermark) => i.message.id > watermark,
+                            None => {
+                                arrived_while_hidden.contains(&i.message.id)
+                            }
+                        }
+                })
+                .map(|i| i.message.id)
+                .collect()
+        })
+    }
+    /// Ids of messages from other users this user hasn't seen yet
+    pub fn unread(&self) -> Signal<Vec<u32>> {
+        self.unread_after(self.own_watermark.into())
+    }
+    /// Capabilities announced by the server in its welcome message
+    pub fn capabilities(&self) -> ReadSignal<Vec<String>> {
+        self.capabilities.read_only()
+    }
+    /// Whether the server speaks a different protocol version and the page
+    /// should be reloaded
+    pub fn reload_required(&self) -> ReadSignal<bool> {
+        self.reload_required.read_only()
+    }
+    /// The last protocol error reported by the server, cleared once a new
+    /// connection is welcomed
+    pub fn error(&self) -> ReadSignal<Option<(ProtocolErrorCode, String)>> {
+        self.error.read_only()
+    }
     pub fn new(
         ready: Signal<ConnectionReadyState>,
         last_message: Signal<Option<ServerMessage>>,
@@ -136,10 +406,35 @@ where
         let typing = RwSignal::new(vec![]);
         let online: RwSignal<Vec<String>> = RwSignal::new(vec![]);
         let observing: RwSignal<Vec<String>> = RwSignal::new(vec![]);
+        let statuses: RwSignal<Vec<UserStatusInfo>> = RwSignal::new(vec![]);
+        let custom_statuses: RwSignal<Vec<UserCustomStatus>> =
+            RwSignal::new(vec![]);
+        let own_watermark = RwSignal::new(None);
+        let page_visible = RwSignal::new(true);
+        let arrived_while_hidden: RwSignal<Vec<u32>> = RwSignal::new(vec![]);
+        let capabilities: RwSignal<Vec<String>> = RwSignal::new(vec![]);
+        let reload_required = RwSignal::new(false);
+        let error = RwSignal::new(None);
+        let find_message = move |id: u32| {
+            messages.with_untracked(|messages| {
+                messages
+                    .iter()
+                    .find(|message| {
+                        message
+                            .with_untracked(|message| message.message.id == id)
+                    })
+                    .cloned()
+            })
+        };
         {
+            let name = name.clone();
             Effect::new(move || {
-                last_message.with(move |last_message| match last_message {
+                last_message.with(|last_message| match last_message {
                     None => (),
+                    // A message sent while joining can arrive both in the
+                    // history and on its own
+                    Some(ServerMessage::MessageSent { message })
+                        if find_message(message.id).is_some() => {}
                     Some(ServerMessage::MessageSent { message }) => {
                         let client_message = UserMessageClient {
                             message: message.clone(),
@@ -147,7 +442,27 @@ where
                         };
                         messages.update(|history| {
                             history.push(ArcRwSignal::new(client_message))
-                        })
+                        });
+                        if !page_visible.get_untracked() {
+                            arrived_while_hidden
+                                .update(|ids| ids.push(message.id));
+                        }
+                    }
+                    Some(ServerMessage::MessageStreaming { id, delta }) => {
+                        if let Some(message) = find_message(*id) {
+                            message.update(|message| {
+                                message.message.message_md.push_str(delta)
+                            });
+                        }
+                    }
+                    Some(ServerMessage::MessageFinalized {
+                        message: finished,
+                    }) => {
+                        if let Some(message) = find_message(finished.id) {
+                            message.update(|message| {
+                                message.message = finished.clone()
+                            });
+                        }
                     }
                     Some(ServerMessage::UserTyping { user }) => {
                         typing.update(move |typing| {
@@ -167,11 +482,11 @@ where
                     Some(ServerMessage::OnlineUsersUpdate { users }) => {
                         online.set(users.clone());
                     }
-                    Some(ServerMessage::MessagesRead { by_user, earliest }) => {
+                    Some(ServerMessage::MessagesRead { by_user, up_to }) => {
                         messages.update(move |messages| {
                             for message in messages
                                 .iter_mut()
-                                .filter(|i| i.get().message.id >= *earliest)
+                                .filter(|i| i.get().message.id <= *up_to)
                             {
                                 if !message.get().read_by.contains(by_user) {
                                     message.update(|v| {
@@ -181,6 +496,37 @@ where
                             }
                         });
                     }
+                    Some(ServerMessage::History {
+                        messages: history,
+                        read_watermarks,
+                    }) => {
+                        arrived_while_hidden.update(|ids| {
+                            ids.retain(|id| {
+                                history.iter().any(|message| message.id == *id)
+                            })
+                        });
+                        let history = history
+                            .iter()
+                            .map(|message| {
+                                let read_by = read_watermarks
+                                    .iter()
+                                    .filter(|i| i.up_to >= message.id)
+                                    .map(|i| i.user.clone())
+                                    .collect();
+                                ArcRwSignal::new(UserMessageClient {
+                                    message: message.clone(),
+                                    read_by,
+                                })
+                            })
+                            .collect();
+                        messages.set(history);
+                        own_watermark.set(
+                            read_watermarks
+                                .iter()
+                                .find(|i| i.user == name)
+                                .map(|i| i.up_to),
+                        );
+                    }
                     Some(ServerMessage::UserObserving { user }) => {
                         if !observing.get_untracked().contains(user) {
                             observing.update(|v| v.push(user.to_string()));
@@ -194,16 +540,53 @@ where
                                 .cloned()
                                 .collect();
                         }),
+                    Some(ServerMessage::Welcome {
+                        server_version,
+                        capabilities: server_capabilities,
+                    }) => {
+                        capabilities.set(server_capabilities.clone());
+                        error.set(None);
+                        if *server_version > PROTOCOL_VERSION {
+                            reload_required.set(true);
+                        }
+                    }
+                    Some(ServerMessage::ReloadRequired { .. }) => {
+                        reload_required.set(true);
+                    }
+                    Some(ServerMessage::UserStatusUpdate {
+                        statuses: changes,
+                    }) => statuses.update(move |statuses| {
+                        for change in changes {
+                            statuses.retain(|i| i.user != change.user);
+                            statuses.push(change.clone());
+                        }
+                    }),
+                    Some(ServerMessage::CustomStatusUpdate {
+                        statuses: changes,
+                    }) => custom_statuses.update(move |statuses| {
+                        for change in changes {
+                            statuses.retain(|i| i.user != change.user);
+                            statuses.push(change.clone());
+                        }
+                    }),
+                    Some(ServerMessage::Error { code, message }) => {
+                        log::error!(
+                            "Server closed connection ({code:?}): {message}"
+                        );
+                        error.set(Some((*code, message.clone())));
+                    }
                 })
             });
         }
         {
             let send = send.clone();
+            let name = name.clone();
             Effect::new(move |prev: Option<bool>| match ready.get() {
                 ConnectionReadyState::Open => {
                     if prev.is_none_or(|v| !v) {
                         send(&ClientMessage::InitMessage {
                             name: name.clone(),
+                            protocol_version: PROTOCOL_VERSION,
                         });
                     }
                     true
@@ -212,6 +595,7 @@ where
             });
         }
         Self {
+            name,
             ready,
             message: last_message,
             send,
@@ -219,6 +603,14 @@ where
             typing,
             online,
             observing,
+            statuses,
+            custom_statuses,
+            own_watermark,
+            page_visible,
+            arrived_while_hidden,
+            capabilities,
+            reload_required,
+            error,
         }
     }
     pub fn send_message(
@@ -241,13 +633,14 @@ where
         };
         (self.send)(&message);
     }
-    pub fn read_messages(&self) {
-        let messages = self.messages.read();
-        let Some(earliest) = messages.first() else {
+    /// Reports that the message with `id` has been seen, unless a later one
+    /// already was
+    pub fn mark_read(&self, id: u32) {
+        if self.own_watermark.get_untracked().is_some_and(|v| v >= id) {
             return;
-        };
-        let earliest = earliest.get_untracked().message.id;
-        (self.send)(&ClientMessage::ReadMessages { earliest });
+        }
+        self.own_watermark.set(Some(id));
+        (self.send)(&ClientMessage::ReadMessages { up_to: id });
     }
     pub fn ready(&self) -> Signal<ConnectionReadyState> {
         self.ready
@@ -255,7 +648,287 @@ where
     pub fn type_(&self) {
         (self.send)(&ClientMessage::Typed);
     }
+    /// Tells whether the page is showing, so that messages arriving while
+    /// it's hidden can count as unread even before there's a watermark
+    pub fn set_page_visible(&self, visible: bool) {
+        self.page_visible.set(visible);
+    }
     pub fn update_visiblity(&self, vis: impl Into<VisibilityState>) {
         (self.send)(&ClientMessage::VisibilityUpdate(vis.into()));
     }
+    pub fn set_custom_status(&self, status: Option<CustomStatus>) {
+        (self.send)(&ClientMessage::SetCustomStatus(status));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use codee::{binary::MsgpackSerdeCodec, HybridDecoder};
+
+    use super::*;
+
+    // Fixtures are messages as encoded by older builds, which newer servers
+    // and clients have to keep understanding for as long as they're supported
+
+    #[test]
+    fn decodes_init_from_before_versioning() {
+        let decoded: ClientMessage = MsgpackSerdeCodec::decode_bin(
+            include_bytes!("../tests/fixtures/protocol/v0_init.msgpack"),
+        )
+        .unwrap();
+        assert!(matches!(
+            decoded,
+            ClientMessage::InitMessage {
+                ref name,
+                protocol_version: 0,
+            } if name == "alice"
+        ));
+    }
+
+    #[test]
+    fn decodes_v1_init() {
+        let decoded: ClientMessage = MsgpackSerdeCodec::decode_bin(
+            include_bytes!("../tests/fixtures/protocol/v1_init.msgpack"),
+        )
+        .unwrap();
+        assert!(matches!(
+            decoded,
+            ClientMessage::InitMessage {
+                ref name,
+                protocol_version: 1,
+            } if name == "alice"
+        ));
+    }
+
+    #[test]
+    fn decodes_v1_welcome() {
+        let decoded: ServerMessage = MsgpackSerdeCodec::decode_bin(
+            include_bytes!("../tests/fixtures/protocol/v1_welcome.msgpack"),
+        )
+        .unwrap();
+        let ServerMessage::Welcome {
+            server_version,
+            capabilities,
+        } = decoded
+        else {
+            panic!("Expected a welcome message, got {decoded:?}");
+        };
+        assert_eq!(server_version, 1);
+        assert_eq!(capabilities, ["read-receipts", "visibility"]);
+    }
+
+    #[test]
+    fn decodes_v1_reload_required() {
+        let decoded: ServerMessage = MsgpackSerdeCodec::decode_bin(
+            include_bytes!(
+                "../tests/fixtures/protocol/v1_reload_required.msgpack"
+            ),
+        )
+        .unwrap();
+        assert!(matches!(
+            decoded,
+            ServerMessage::ReloadRequired { server_version: 1 }
+        ));
+    }
+
+    #[test]
+    fn decodes_v5_init() {
+        let decoded: ClientMessage = MsgpackSerdeCodec::decode_bin(
+            include_bytes!("../tests/fixtures/protocol/v5_init.msgpack"),
+        )
+        .unwrap();
+        assert!(matches!(
+            decoded,
+            ClientMessage::InitMessage {
+                ref name,
+                protocol_version: 5,
+            } if name == "alice"
+        ));
+    }
+
+    #[test]
+    fn decodes_v5_welcome() {
+        let decoded: ServerMessage = MsgpackSerdeCodec::decode_bin(
+            include_bytes!("../tests/fixtures/protocol/v5_welcome.msgpack"),
+        )
+        .unwrap();
+        let ServerMessage::Welcome {
+            server_version,
+            capabilities,
+        } = decoded
+        else {
+            panic!("Expected a welcome message, got {decoded:?}");
+        };
+        assert_eq!(server_version, 5);
+        assert_eq!(
+            capabilities,
+            ["read-receipts", "visibility", "bots", "streaming"]
+        );
+    }
+
+    /// The message every message in the v5 fixtures carries
+    fn assert_fixture_message(message: &UserMessage) {
+        assert_eq!(message.send_time.to_rfc3339(), "2025-01-31T09:00:00+00:00");
+        assert_eq!(message.sender, "alice");
+        assert_eq!(message.message_md, "Hello *there*");
+        assert_eq!(message.message_short.as_deref(), Some("Hello there"));
+        assert_eq!(
+            message.message_html_safe.as_deref(),
+            Some("<p>Hello <em>there</em></p>")
+        );
+        assert_eq!(message.reply_to, Some(3));
+        assert_eq!(message.id, 4);
+    }
+
+    #[test]
+    fn decodes_v5_client_messages() {
+        let decoded: Vec<ClientMessage> = MsgpackSerdeCodec::decode_bin(
+            include_bytes!(
+                "../tests/fixtures/protocol/v5_client_messages.msgpack"
+            ),
+        )
+        .unwrap();
+        assert_eq!(decoded.len(), 6);
+        let ClientMessage::SendMessage { ref message } = decoded[0] else {
+            panic!("Expected a sent message, got {:?}", decoded[0]);
+        };
+        assert_fixture_message(message);
+        assert!(matches!(decoded[1], ClientMessage::Typed));
+        assert!(matches!(
+            decoded[2],
+            ClientMessage::ReadMessages { up_to: 4 }
+        ));
+        assert!(matches!(
+            decoded[3],
+            ClientMessage::VisibilityUpdate(VisibilityState::Hidden)
+        ));
+        let ClientMessage::SetCustomStatus(Some(ref status)) = decoded[4]
+        else {
+            panic!("Expected a custom status, got {:?}", decoded[4]);
+        };
+        assert_eq!(status.emoji.as_deref(), Some("🍜"));
+        assert_eq!(status.text, "lunch");
+        assert!(status.expires.is_some());
+        assert!(matches!(decoded[5], ClientMessage::SetCustomStatus(None)));
+    }
+
+    #[test]
+    fn decodes_v5_server_messages() {
+        let decoded: Vec<ServerMessage> = MsgpackSerdeCodec::decode_bin(
+            include_bytes!(
+                "../tests/fixtures/protocol/v5_server_messages.msgpack"
+            ),
+        )
+        .unwrap();
+        assert_eq!(decoded.len(), 14);
+        assert!(matches!(
+            decoded[0],
+            ServerMessage::MessagesRead { ref by_user, up_to: 4 }
+                if by_user == "bob"
+        ));
+        let ServerMessage::MessageSent { ref message } = decoded[1] else {
+            panic!("Expected a sent message, got {:?}", decoded[1]);
+        };
+        assert_fixture_message(message);
+        assert!(matches!(
+            decoded[2],
+            ServerMessage::MessageStreaming { id: 4, ref delta }
+                if delta == "Hel"
+        ));
+        let ServerMessage::MessageFinalized { ref message } = decoded[3] else {
+            panic!("Expected a finalized message, got {:?}", decoded[3]);
+        };
+        assert_fixture_message(message);
+        assert!(matches!(decoded[4], ServerMessage::UserTyping { .. }));
+        assert!(matches!(
+            decoded[5],
+            ServerMessage::UserStoppedTyping { .. }
+        ));
+        assert!(matches!(
+            decoded[6],
+            ServerMessage::OnlineUsersUpdate { ref users }
+                if users == &["alice", "bob"]
+        ));
+        assert!(matches!(decoded[7], ServerMessage::UserObserving { .. }));
+        assert!(matches!(decoded[8], ServerMessage::UserNotObserving { .. }));
+        assert!(matches!(
+            decoded[9],
+            ServerMessage::ReloadRequired { server_version: 5 }
+        ));
+        let ServerMessage::History {
+            ref messages,
+            ref read_watermarks,
+        } = decoded[10]
+        else {
+            panic!("Expected the history, got {:?}", decoded[10]);
+        };
+        assert_fixture_message(&messages[0]);
+        assert_eq!(read_watermarks[0].user, "bob");
+        assert_eq!(read_watermarks[0].up_to, 4);
+        let ServerMessage::UserStatusUpdate { ref statuses } = decoded[11]
+        else {
+            panic!("Expected user statuses, got {:?}", decoded[11]);
+        };
+        assert_eq!(statuses[0].status, UserStatus::Idle);
+        let ServerMessage::CustomStatusUpdate { ref statuses } = decoded[12]
+        else {
+            panic!("Expected custom statuses, got {:?}", decoded[12]);
+        };
+        assert_eq!(statuses[0].user, "bob");
+        assert!(statuses[0].status.is_some());
+        assert!(matches!(
+            decoded[13],
+            ServerMessage::Error {
+                code: ProtocolErrorCode::DuplicateInit,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn decodes_current_init() {
+        let decoded: ClientMessage = MsgpackSerdeCodec::decode_bin(
+            include_bytes!("../tests/fixtures/protocol/v6_init.msgpack"),
+        )
+        .unwrap();
+        assert!(matches!(
+            decoded,
+            ClientMessage::InitMessage {
+                protocol_version: PROTOCOL_VERSION,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn decodes_current_invalid_name_error() {
+        let decoded: ServerMessage = MsgpackSerdeCodec::decode_bin(
+            include_bytes!(
+                "../tests/fixtures/protocol/v6_invalid_name.msgpack"
+            ),
+        )
+        .unwrap();
+        assert!(matches!(
+            decoded,
+            ServerMessage::Error {
+                code: ProtocolErrorCode::InvalidName,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn server_and_bot_names_are_invalid() {
+        for name in ["System", " system ", "helper (Bot)", "Admin", "  "] {
+            assert!(!validate_name(name), "{name:?} should be invalid");
+        }
+    }
+
+    #[test]
+    fn names_containing_reserved_words_are_valid() {
+        for name in ["alice", "Badminton", "Systemsguy", "Bot fan", "sysadmin"]
+        {
+            assert!(validate_name(name), "{name:?} should be valid");
+        }
+    }
 }
@@ -0,0 +1,428 @@
+use async_openai::{
+    error::OpenAIError,
+    types::{ChatCompletionTool, ChatCompletionToolArgs, FunctionObjectArgs},
+};
+use rss_chat::socket::{UserMessage, UserStatus};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::ServerStateMessage;
+
+/// Most messages a single history search returns
+const MAX_SEARCH_RESULTS: usize = 20;
+/// Characters of each message shown in search results
+const MAX_SEARCH_RESULT_CHARS: usize = 300;
+/// Longest expression the calculator accepts, which also bounds how deeply
+/// it can recurse
+const MAX_EXPRESSION_LEN: usize = 1000;
+
+/// Functions bots can call to look things up on the server while answering
+#[derive(Debug, Clone, Copy)]
+enum Tool {
+    SearchHistory,
+    OnlineUsers,
+    CurrentTime,
+    Calculator,
+}
+
+impl Tool {
+    const ALL: [Tool; 4] = [
+        Tool::SearchHistory,
+        Tool::OnlineUsers,
+        Tool::CurrentTime,
+        Tool::Calculator,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Tool::SearchHistory => "search_chat_history",
+            Tool::OnlineUsers => "get_online_users",
+            Tool::CurrentTime => "get_current_time",
+            Tool::Calculator => "calculate",
+        }
+    }
+    fn from_name(name: &str) -> Option<Tool> {
+        Self::ALL.into_iter().find(|tool| tool.name() == name)
+    }
+    fn description(self) -> &'static str {
+        match self {
+            Tool::SearchHistory => {
+                "Searches recent chat messages for some text, newest first"
+            }
+            Tool::OnlineUsers => {
+                "Lists the users currently in the chat and whether they're \
+active, idle or away"
+            }
+            Tool::CurrentTime => "Gets the current date and time in UTC",
+            Tool::Calculator => {
+                "Evaluates an arithmetic expression with + - * / % ^ and \
+parentheses"
+            }
+        }
+    }
+    /// JSON schema of the tool's arguments
+    fn parameters(self) -> serde_json::Value {
+        match self {
+            Tool::SearchHistory => json!({
+                "type": "object",
+                "properties": {
+                    "query": {
+                        "type": "string",
+                        "description": "Text to look for, ignoring case",
+                    },
+                    "limit": {
+                        "type": "integer",
+                        "description": format!(
+                            "Most messages to return, at most \
+{MAX_SEARCH_RESULTS}"
+                        ),
+                    },
+                },
+                "required": ["query"],
+            }),
+            Tool::OnlineUsers | Tool::CurrentTime => json!({
+                "type": "object",
+                "properties": {},
+            }),
+            Tool::Calculator => json!({
+                "type": "object",
+                "properties": {
+                    "expression": {
+                        "type": "string",
+                        "description": "The expression, like (2 + 3) * 4",
+                    },
+                },
+                "required": ["expression"],
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchArgs {
+    query: String,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct CalculatorArgs {
+    expression: String,
+}
+
+/// Runs tool calls against the server state
+pub struct ToolRegistry {
+    state_tx: mpsc::Sender<ServerStateMessage>,
+}
+
+impl ToolRegistry {
+    pub fn new(state_tx: mpsc::Sender<ServerStateMessage>) -> ToolRegistry {
+        ToolRegistry { state_tx }
+    }
+    /// Descriptions of all tools, to send along with completion requests
+    pub fn definitions(&self) -> Result<Vec<ChatCompletionTool>, OpenAIError> {
+        Tool::ALL
+            .into_iter()
+            .map(|tool| {
+                ChatCompletionToolArgs::default()
+                    .function(
+                        FunctionObjectArgs::default()
+                            .name(tool.name())
+                            .description(tool.description())
+                            .parameters(tool.parameters())
+                            .build()?,
+                    )
+                    .build()
+            })
+            .collect()
+    }
+    /// Runs a tool with the JSON arguments the model gave it. Failures are
+    /// described in the result so the model can correct itself.
+    pub async fn call(&self, name: &str, arguments: &str) -> String {
+        let Some(tool) = Tool::from_name(name) else {
+            return format!("Error: there is no tool named \"{name}\"");
+        };
+        let result = match tool {
+            Tool::SearchHistory => match serde_json::from_str(arguments) {
+                Ok(args) => self.search_history(args).await,
+                Err(e) => Err(format!("invalid arguments: {e}")),
+            },
+            Tool::OnlineUsers => self.online_users().await,
+            Tool::CurrentTime => Ok(chrono::Utc::now().to_rfc3339()),
+            Tool::Calculator => {
+                match serde_json::from_str::<CalculatorArgs>(arguments) {
+                    Ok(args) => calculate(&args.expression)
+                        .map(|value| value.to_string()),
+                    Err(e) => Err(format!("invalid arguments: {e}")),
+                }
+            }
+        };
+        result.unwrap_or_else(|e| format!("Error: {e}"))
+    }
+    async fn search_history(&self, args: SearchArgs) -> Result<String, String> {
+        let limit = args
+            .limit
+            .unwrap_or(MAX_SEARCH_RESULTS)
+            .min(MAX_SEARCH_RESULTS);
+        let (messages_tx, messages_rx) = oneshot::channel();
+        self.state_tx
+            .send(ServerStateMessage::SearchMessages {
+                query: args.query,
+                limit,
+                messages_tx,
+            })
+            .await
+            .map_err(|_| "the chat is unavailable".to_string())?;
+        let messages: Vec<UserMessage> = messages_rx
+            .await
+            .map_err(|_| "the chat is unavailable".to_string())?;
+        if messages.is_empty() {
+            return Ok("No messages found".to_string());
+        }
+        Ok(messages
+            .iter()
+            .map(|message| {
+                let mut text: String = message
+                    .message_md
+                    .chars()
+                    .take(MAX_SEARCH_RESULT_CHARS)
+                    .collect();
+                if text.len() < message.message_md.len() {
+                    text.push_str("...");
+                }
+                format!(
+                    "[{}] {}: {text}",
+                    message.send_time.format("%Y-%m-%d %H:%M UTC"),
+                    message.sender
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+    async fn online_users(&self) -> Result<String, String> {
+        let (users_tx, users_rx) = oneshot::channel();
+        self.state_tx
+            .send(ServerStateMessage::OnlineUsers { users_tx })
+            .await
+            .map_err(|_| "the chat is unavailable".to_string())?;
+        let users = users_rx
+            .await
+            .map_err(|_| "the chat is unavailable".to_string())?;
+        if users.is_empty() {
+            return Ok("Nobody is online".to_string());
+        }
+        Ok(users
+            .iter()
+            .map(|info| {
+                let status = match info.status {
+                    UserStatus::Active => "active",
+                    UserStatus::Idle => "idle",
+                    UserStatus::Away => "away",
+                    UserStatus::Offline => "offline",
+                };
+                format!("{}: {status}", info.user)
+            })
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+}
+
+/// Evaluates arithmetic with `+ - * / % ^`, parentheses and negation
+fn calculate(expression: &str) -> Result<f64, String> {
+    if expression.len() > MAX_EXPRESSION_LEN {
+        return Err("the expression is too long".to_string());
+    }
+    let mut calculator = Calculator {
+        chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+        pos: 0,
+    };
+    let value = calculator.sum()?;
+    if let Some(c) = calculator.peek() {
+        return Err(format!("unexpected '{c}'"));
+    }
+    if !value.is_finite() {
+        return Err("the result is not a finite number".to_string());
+    }
+    Ok(value)
+}
+
+/// Recursive descent parser that evaluates as it goes
+struct Calculator {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Calculator {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+    fn eat(&mut self, c: char) -> bool {
+        let matches = self.peek() == Some(c);
+        if matches {
+            self.pos += 1;
+        }
+        matches
+    }
+    /// sum = product (("+" | "-") product)*
+    fn sum(&mut self) -> Result<f64, String> {
+        let mut value = self.product()?;
+        loop {
+            if self.eat('+') {
+                value += self.product()?;
+            } else if self.eat('-') {
+                value -= self.product()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+    /// product = factor (("*" | "/" | "%") factor)*
+    fn product(&mut self) -> Result<f64, String> {
+        let mut value = self.factor()?;
+        loop {
+            if self.eat('*') {
+                value *= self.factor()?;
+            } else if self.eat('/') {
+                value /= self.factor()?;
+            } else if self.eat('%') {
+                value %= self.factor()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+    /// factor = "-" factor | power
+    ///
+    /// Negation binds looser than powers, so -2^2 is -4
+    fn factor(&mut self) -> Result<f64, String> {
+        if self.eat('-') {
+            Ok(-self.factor()?)
+        } else {
+            self.power()
+        }
+    }
+    /// power = atom ("^" factor)?
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.atom()?;
+        if self.eat('^') {
+            Ok(base.powf(self.factor()?))
+        } else {
+            Ok(base)
+        }
+    }
+    /// atom = number | "(" sum ")"
+    fn atom(&mut self) -> Result<f64, String> {
+        if self.eat('(') {
+            let value = self.sum()?;
+            if !self.eat(')') {
+                return Err("missing ')'".to_string());
+            }
+            return Ok(value);
+        }
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(match self.peek() {
+                Some(c) => format!("unexpected '{c}'"),
+                None => "unexpected end of the expression".to_string(),
+            });
+        }
+        let number: String = self.chars[start..self.pos].iter().collect();
+        number
+            .parse()
+            .map_err(|_| format!("invalid number \"{number}\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn products_bind_tighter_than_sums() {
+        assert_eq!(calculate("2 + 3 * 4"), Ok(14.0));
+        assert_eq!(calculate("(2 + 3) * 4"), Ok(20.0));
+        assert_eq!(calculate("10 - 4 - 3"), Ok(3.0));
+        assert_eq!(calculate("2 * 3 ^ 2"), Ok(18.0));
+        assert_eq!(calculate("2 ^ 3 ^ 2"), Ok(512.0));
+        assert_eq!(calculate("7 % 4 * 2"), Ok(6.0));
+    }
+
+    #[test]
+    fn negation_binds_looser_than_powers() {
+        assert_eq!(calculate("-2 ^ 2"), Ok(-4.0));
+        assert_eq!(calculate("(-2) ^ 2"), Ok(4.0));
+        assert_eq!(calculate("2 ^ -1"), Ok(0.5));
+        assert_eq!(calculate("3 - -2"), Ok(5.0));
+        assert_eq!(calculate("--3"), Ok(3.0));
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        assert_eq!(
+            calculate("1 / 0"),
+            Err("the result is not a finite number".to_string())
+        );
+        assert_eq!(
+            calculate("0 / 0"),
+            Err("the result is not a finite number".to_string())
+        );
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        assert_eq!(
+            calculate("10 ^ 400"),
+            Err("the result is not a finite number".to_string())
+        );
+        assert_eq!(
+            calculate("-(10 ^ 200) * 10 ^ 200"),
+            Err("the result is not a finite number".to_string())
+        );
+    }
+
+    #[test]
+    fn malformed_expressions_are_errors() {
+        assert_eq!(
+            calculate(""),
+            Err("unexpected end of the expression".into())
+        );
+        assert_eq!(
+            calculate("2 +"),
+            Err("unexpected end of the expression".into())
+        );
+        assert_eq!(calculate("(2 + 3"), Err("missing ')'".to_string()));
+        assert_eq!(calculate("2 + 3)"), Err("unexpected ')'".to_string()));
+        assert_eq!(calculate("2 * x"), Err("unexpected 'x'".to_string()));
+        assert_eq!(calculate("1.2.3"), Err("invalid number \"1.2.3\"".into()));
+        let too_long = "1+".repeat(MAX_EXPRESSION_LEN) + "1";
+        assert_eq!(
+            calculate(&too_long),
+            Err("the expression is too long".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn calls_report_errors_to_the_model() {
+        let (state_tx, _) = mpsc::channel(1);
+        let tools = ToolRegistry::new(state_tx);
+        assert_eq!(
+            tools.call("calculate", r#"{"expression": "6 * 7"}"#).await,
+            "42"
+        );
+        assert_eq!(
+            tools.call("calculate", r#"{"expression": "1 / 0"}"#).await,
+            "Error: the result is not a finite number"
+        );
+        assert!(tools
+            .call("calculate", "not json")
+            .await
+            .starts_with("Error: invalid arguments"));
+        assert_eq!(
+            tools.call("fly", "{}").await,
+            "Error: there is no tool named \"fly\""
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use thiserror::Error;
+
+/// Tokens used by one or more completion requests
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl TokenUsage {
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+    pub fn add(&mut self, other: TokenUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+impl From<&async_openai::types::CompletionUsage> for TokenUsage {
+    fn from(usage: &async_openai::types::CompletionUsage) -> Self {
+        TokenUsage {
+            prompt_tokens: usage.prompt_tokens.into(),
+            completion_tokens: usage.completion_tokens.into(),
+        }
+    }
+}
+
+/// How much each user may ask of bots. `None` means unlimited.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UsageLimits {
+    pub requests_per_minute: Option<usize>,
+    pub daily_tokens: Option<u64>,
+}
+
+impl UsageLimits {
+    /// Reads the limits from `AI_USER_REQUESTS_PER_MINUTE` and
+    /// `AI_USER_DAILY_TOKENS`
+    pub fn from_env() -> UsageLimits {
+        fn read<T: std::str::FromStr>(key: &str) -> Option<T> {
+            let value = std::env::var(key).ok()?;
+            let parsed = value.parse().ok();
+            if parsed.is_none() {
+                log::error!("Invalid {key} \"{value}\"");
+            }
+            parsed
+        }
+        UsageLimits {
+            requests_per_minute: read("AI_USER_REQUESTS_PER_MINUTE"),
+            daily_tokens: read("AI_USER_DAILY_TOKENS"),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum UsageLimitError {
+    #[error("Too many requests, try again in {0} seconds")]
+    RateLimited(i64),
+    #[error("Daily budget of {0} tokens used up, try again tomorrow")]
+    DailyBudgetExceeded(u64),
+}
+
+#[derive(Default)]
+struct UserUsage {
+    /// Day that `today` counts tokens for
+    day: Option<NaiveDate>,
+    today: TokenUsage,
+    total: TokenUsage,
+    /// Times of the requests made within the last minute
+    recent_requests: VecDeque<DateTime<Utc>>,
+}
+
+impl UserUsage {
+    /// Forgets what no longer counts against the limits at `now`
+    fn expire(&mut self, now: DateTime<Utc>) {
+        if self.day != Some(now.date_naive()) {
+            self.day = Some(now.date_naive());
+            self.today = TokenUsage::default();
+        }
+        while self
+            .recent_requests
+            .front()
+            .is_some_and(|time| now - *time >= chrono::TimeDelta::minutes(1))
+        {
+            self.recent_requests.pop_front();
+        }
+    }
+}
+
+/// What a user has used, for showing to them
+pub struct UserUsageSummary {
+    pub today: TokenUsage,
+    pub total: TokenUsage,
+    pub requests_last_minute: usize,
+}
+
+/// Token usage of every user and bot since the server started, and the
+/// limits users are held to
+#[derive(Default)]
+pub struct UsageTracker {
+    limits: UsageLimits,
+    users: HashMap<String, UserUsage>,
+    bots: HashMap<String, TokenUsage>,
+}
+
+impl UsageTracker {
+    pub fn new(limits: UsageLimits) -> UsageTracker {
+        UsageTracker {
+            limits,
+            ..Default::default()
+        }
+    }
+    pub fn limits(&self) -> UsageLimits {
+        self.limits
+    }
+    /// Checks whether `user` may make another request right now
+    pub fn check(
+        &mut self,
+        user: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), UsageLimitError> {
+        let usage = self.users.entry(user.to_string()).or_default();
+        usage.expire(now);
+        if let Some(daily_tokens) = self.limits.daily_tokens
+            && usage.today.total() >= daily_tokens
+        {
+            return Err(UsageLimitError::DailyBudgetExceeded(daily_tokens));
+        }
+        if let Some(requests_per_minute) = self.limits.requests_per_minute
+            && usage.recent_requests.len() >= requests_per_minute
+        {
+            let retry_at = usage
+                .recent_requests
+                .front()
+                .map_or(now, |time| *time + chrono::TimeDelta::minutes(1));
+            return Err(UsageLimitError::RateLimited(
+                (retry_at - now).num_seconds().max(1),
+            ));
+        }
+        Ok(())
+    }
+    /// Checks whether `user` may make another request and counts it if so
+    pub fn start_request(
+        &mut self,
+        user: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), UsageLimitError> {
+        self.check(user, now)?;
+        if let Some(usage) = self.users.get_mut(user) {
+            usage.recent_requests.push_back(now);
+        }
+        Ok(())
+    }
+    /// Adds the tokens a request used to its user and bot
+    pub fn record(&mut self, user: &str, bot: &str, tokens: TokenUsage) {
+        let usage = self.users.entry(user.to_string()).or_default();
+        usage.expire(Utc::now());
+        usage.today.add(tokens);
+        usage.total.add(tokens);
+        self.bots.entry(bot.to_string()).or_default().add(tokens);
+    }
+    pub fn user_summary(
+        &mut self,
+        user: &str,
+        now: DateTime<Utc>,
+    ) -> UserUsageSummary {
+        let usage = self.users.entry(user.to_string()).or_default();
+        usage.expire(now);
+        UserUsageSummary {
+            today: usage.today,
+            total: usage.total,
+            requests_last_minute: usage.recent_requests.len(),
+        }
+    }
+    /// Tokens used by each bot, most first
+    pub fn bot_totals(&self) -> Vec<(String, TokenUsage)> {
+        let mut totals: Vec<(String, TokenUsage)> = self
+            .bots
+            .iter()
+            .map(|(bot, usage)| (bot.clone(), *usage))
+            .collect();
+        totals.sort_by_key(|(_, usage)| std::cmp::Reverse(usage.total()));
+        totals
+    }
+}